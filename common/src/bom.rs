@@ -0,0 +1,330 @@
+use std::{collections::BTreeMap, fmt::Write};
+
+use weldr::{Command, SourceMap};
+
+use crate::{
+    hierarchy::{Hierarchy, is_part},
+    ldr::{
+        Ancestors, CURRENT_COLOR, ColorCode, ColorMap, TraverseError, get_model, new_color,
+        reference_number,
+    },
+    resolver::normalize,
+    steps::steps,
+};
+
+/// A bill of materials: how many of each part are needed, in each color.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Bom {
+    /// Quantities by normalized part filename and color.
+    items: BTreeMap<(String, ColorCode), usize>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BomEntry<'a> {
+    pub part: &'a str,
+    pub color: ColorCode,
+    pub quantity: usize,
+}
+
+impl Bom {
+    /// Counts every part in a model, across all instances of its submodels.
+    ///
+    /// Parts in the inherited color take the color of the submodel instance containing them.
+    pub fn build(source_map: &SourceMap, model_name: &str) -> Result<Self, TraverseError> {
+        let mut bom = Self::default();
+        bom.add_model(
+            source_map,
+            model_name,
+            CURRENT_COLOR,
+            &mut Ancestors::default(),
+        )?;
+        Ok(bom)
+    }
+
+    /// The parts each submodel references directly, not counting child submodels.
+    /// Colors are as the submodel gives them, so inherited ones stay as code 16.
+    pub fn per_submodel(hierarchy: &Hierarchy) -> BTreeMap<String, Self> {
+        let mut output = BTreeMap::new();
+        for submodel in hierarchy.submodels.values() {
+            let mut bom = Self::default();
+            for part in &submodel.parts {
+                bom.add(&part.file, part.color, 1);
+            }
+            output.insert(submodel.name.clone(), bom);
+        }
+        output
+    }
+
+    /// The parts added in each step of a model.
+    /// Submodels are listed in the steps that build them, not those that place them,
+    /// and colors are as the model gives them.
+    pub fn per_step(source_map: &SourceMap, model_name: &str) -> Result<Vec<Self>, TraverseError> {
+        let steps = steps(source_map, model_name)?;
+        let boms = steps.steps.iter().map(|step| {
+            let mut bom = Self::default();
+            for placement in steps.added(step) {
+                if is_part(source_map, &placement.file) {
+                    bom.add(&placement.file, placement.color, 1);
+                }
+            }
+            bom
+        });
+        Ok(boms.collect())
+    }
+
+    fn add_model<'a>(
+        &mut self,
+        source_map: &'a SourceMap,
+        model_name: &'a str,
+        color: ColorCode,
+        ancestors: &mut Ancestors<'a>,
+    ) -> Result<(), TraverseError> {
+        let model = get_model(source_map, model_name)?;
+        ancestors.enter(model_name)?;
+        for (i, cmd) in model.cmds.iter().enumerate() {
+            let Command::SubFileRef(sfrc) = cmd else {
+                continue;
+            };
+            let child_color = new_color(color, sfrc.color);
+            if is_part(source_map, &sfrc.file) {
+                self.add(&sfrc.file, child_color, 1);
+            } else {
+                self.add_model(source_map, &sfrc.file, child_color, ancestors)
+                    .map_err(|e| e.referenced_by(model_name, reference_number(model, i)))?;
+            }
+        }
+        ancestors.leave();
+        Ok(())
+    }
+
+    pub fn add(&mut self, part: &str, color: ColorCode, quantity: usize) {
+        *self.items.entry((normalize(part), color)).or_default() += quantity;
+    }
+
+    /// Adds every item of another bill of materials.
+    pub fn merge(&mut self, other: &Self) {
+        for entry in other.entries() {
+            self.add(entry.part, entry.color, entry.quantity);
+        }
+    }
+
+    /// Items ordered by part, then color.
+    pub fn entries(&self) -> impl Iterator<Item = BomEntry<'_>> {
+        self.items
+            .iter()
+            .map(|((part, color), &quantity)| BomEntry {
+                part,
+                color: *color,
+                quantity,
+            })
+    }
+
+    /// The total number of parts.
+    pub fn total(&self) -> usize {
+        self.items.values().sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    /// Writes `part,color,quantity` rows, with a header.
+    pub fn to_csv(&self) -> String {
+        let mut output = String::from("part,color,quantity\n");
+        self.write_csv_rows(&mut output, "");
+        output
+    }
+
+    fn write_csv_rows(&self, output: &mut String, prefix: &str) {
+        for entry in self.entries() {
+            let part = csv_escape(entry.part);
+            writeln!(output, "{prefix}{part},{},{}", entry.color, entry.quantity).unwrap();
+        }
+    }
+
+    /// Writes an array of `{"part", "color", "quantity"}` objects.
+    pub fn to_json(&self) -> String {
+        let mut output = self.json_array("");
+        output.push('\n');
+        output
+    }
+
+    fn json_array(&self, indent: &str) -> String {
+        let items = self
+            .entries()
+            .map(|entry| {
+                format!(
+                    r#"{indent}  {{"part": "{}", "color": {}, "quantity": {}}}"#,
+                    json_escape(entry.part),
+                    entry.color,
+                    entry.quantity,
+                )
+            })
+            .collect::<Vec<_>>();
+        if items.is_empty() {
+            return "[]".into();
+        }
+        format!("[\n{}\n{indent}]", items.join(",\n"))
+    }
+
+    /// Writes a BrickLink wanted list, for uploading through its XML import.
+    ///
+    /// Part numbers are LDraw's, which mostly match BrickLink's. Colors BrickLink has no code for
+    /// in Stud.io's definitions are left out, so BrickLink will ask for any color.
+    pub fn to_bricklink_xml(&self, colors: &ColorMap) -> String {
+        let mut output = String::from("<INVENTORY>\n");
+        for entry in self.entries() {
+            let id = entry.part.strip_suffix(".dat").unwrap_or(entry.part);
+            output.push_str("  <ITEM>\n");
+            output.push_str("    <ITEMTYPE>P</ITEMTYPE>\n");
+            writeln!(output, "    <ITEMID>{}</ITEMID>", xml_escape(id)).unwrap();
+            if let Some(color) = colors.bricklink_code(entry.color) {
+                writeln!(output, "    <COLOR>{color}</COLOR>").unwrap();
+            }
+            writeln!(output, "    <MINQTY>{}</MINQTY>", entry.quantity).unwrap();
+            output.push_str("  </ITEM>\n");
+        }
+        output.push_str("</INVENTORY>\n");
+        output
+    }
+}
+
+/// Writes several bills of materials, such as from [`Bom::per_step`] or [`Bom::per_submodel`],
+/// as one CSV with an extra leading column naming the group each row belongs to.
+pub fn breakdown_to_csv<'a>(
+    column: &str,
+    groups: impl IntoIterator<Item = (impl AsRef<str>, &'a Bom)>,
+) -> String {
+    let mut output = format!("{},part,color,quantity\n", csv_escape(column));
+    for (name, bom) in groups {
+        let prefix = format!("{},", csv_escape(name.as_ref()));
+        bom.write_csv_rows(&mut output, &prefix);
+    }
+    output
+}
+
+/// Writes several bills of materials as a JSON object of [`Bom::to_json`] arrays, by group name.
+pub fn breakdown_to_json<'a>(
+    groups: impl IntoIterator<Item = (impl AsRef<str>, &'a Bom)>,
+) -> String {
+    let fields = groups
+        .into_iter()
+        .map(|(name, bom)| {
+            format!(
+                r#"  "{}": {}"#,
+                json_escape(name.as_ref()),
+                bom.json_array("  ")
+            )
+        })
+        .collect::<Vec<_>>();
+    if fields.is_empty() {
+        return "{}\n".into();
+    }
+    format!("{{\n{}\n}}\n", fields.join(",\n"))
+}
+
+fn csv_escape(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_owned()
+    }
+}
+
+fn json_escape(s: &str) -> String {
+    let mut output = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => output.push_str("\\\""),
+            '\\' => output.push_str("\\\\"),
+            c if c.is_control() => write!(output, "\\u{:04x}", c as u32).unwrap(),
+            c => output.push(c),
+        }
+    }
+    output
+}
+
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ldr::Palette;
+
+    #[test]
+    fn csv_quoting() {
+        assert_eq!(csv_escape("3001.dat"), "3001.dat");
+        assert_eq!(csv_escape("a,b"), "\"a,b\"");
+        assert_eq!(csv_escape("say \"hi\""), "\"say \"\"hi\"\"\"");
+        assert_eq!(csv_escape("two\nlines"), "\"two\nlines\"");
+        assert_eq!(csv_escape("carriage\rreturn"), "\"carriage\rreturn\"");
+        assert_eq!(csv_escape(""), "");
+    }
+
+    #[test]
+    fn json_escaping() {
+        assert_eq!(json_escape("s\\3001s01.dat"), "s\\\\3001s01.dat");
+        assert_eq!(json_escape("\"quoted\""), "\\\"quoted\\\"");
+        assert_eq!(json_escape("tab\there"), "tab\\u0009here");
+        assert_eq!(json_escape("brique"), "brique");
+    }
+
+    #[test]
+    fn exports() {
+        let mut bom = Bom::default();
+        bom.add("3001.DAT", 4, 2);
+        bom.add("3001.dat", 4, 1);
+        bom.add("a,b.dat", 1, 1);
+        assert_eq!(
+            bom.to_csv(),
+            "part,color,quantity\n3001.dat,4,3\n\"a,b.dat\",1,1\n"
+        );
+        assert_eq!(
+            bom.to_json(),
+            concat!(
+                "[\n",
+                "  {\"part\": \"3001.dat\", \"color\": 4, \"quantity\": 3},\n",
+                "  {\"part\": \"a,b.dat\", \"color\": 1, \"quantity\": 1}\n",
+                "]\n",
+            )
+        );
+        assert_eq!(Bom::default().to_json(), "[]\n");
+    }
+
+    #[test]
+    fn bricklink_xml() {
+        let mut colors = ColorMap::default();
+        colors
+            .add_studio(
+                "Studio Color Code,RGB value,BL Color Code\n4,#C91A09,5\n",
+                Palette::Rgb,
+            )
+            .unwrap();
+
+        let mut bom = Bom::default();
+        bom.add("3001.dat", 4, 2);
+        bom.add("s/x<y>&z.dat", 71, 1);
+        assert_eq!(
+            bom.to_bricklink_xml(&colors),
+            concat!(
+                "<INVENTORY>\n",
+                "  <ITEM>\n",
+                "    <ITEMTYPE>P</ITEMTYPE>\n",
+                "    <ITEMID>3001</ITEMID>\n",
+                "    <COLOR>5</COLOR>\n",
+                "    <MINQTY>2</MINQTY>\n",
+                "  </ITEM>\n",
+                "  <ITEM>\n",
+                "    <ITEMTYPE>P</ITEMTYPE>\n",
+                "    <ITEMID>s/x&lt;y&gt;&amp;z</ITEMID>\n",
+                "    <MINQTY>1</MINQTY>\n",
+                "  </ITEM>\n",
+                "</INVENTORY>\n",
+            )
+        );
+    }
+}
@@ -0,0 +1,308 @@
+use std::{
+    collections::HashSet,
+    fmt::Write,
+    hash::{DefaultHasher, Hash, Hasher},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, UNIX_EPOCH},
+};
+
+use weldr::{Command, FileRefResolver, Mat4, SourceMap};
+
+use crate::{
+    ldr::{Bfc, CURRENT_COLOR, EDGE_COLOR, GeometryContext, Winding, new_color},
+    resolver::Resolver,
+    writer::write_reference,
+};
+
+const MAGIC: &str = "0 // ldr2pdf part cache";
+const DEPENDENCY: &str = "0 // depends ";
+/// The last line of every entry, so one cut short by a crash is never taken for a whole one.
+const END: &str = "0 // end of ldr2pdf part cache";
+
+/// Serves library parts pre-flattened into a single file of plain geometry,
+/// stored on disk so later runs don't have to parse every subpart and primitive again.
+///
+/// Everything else, custom parts included, is passed through to the underlying [`Resolver`].
+pub struct PartCache<'a> {
+    resolver: &'a Resolver,
+    dir: PathBuf,
+    /// Library files parsed while flattening, shared so each primitive is only parsed once.
+    scratch: Mutex<SourceMap>,
+}
+
+impl<'a> PartCache<'a> {
+    pub fn new(resolver: &'a Resolver) -> Self {
+        let dir = dirs::cache_dir()
+            .unwrap_or_else(std::env::temp_dir)
+            .join("ldr2pdf/parts");
+        Self::with_dir(resolver, dir)
+    }
+
+    pub fn with_dir(resolver: &'a Resolver, dir: impl Into<PathBuf>) -> Self {
+        Self {
+            resolver,
+            dir: dir.into(),
+            scratch: Mutex::new(SourceMap::new()),
+        }
+    }
+
+    fn entry_path(&self, filename: &str, source: &Path) -> PathBuf {
+        // DefaultHasher isn't guaranteed stable across Rust releases, which only costs a rebuild
+        let mut hasher = DefaultHasher::new();
+        filename.to_lowercase().hash(&mut hasher);
+        source.hash(&mut hasher);
+        self.resolver.hash_search_order(&mut hasher);
+        self.dir.join(format!("{:016x}.ldr", hasher.finish()))
+    }
+
+    /// Parses and flattens a part, returning its contents if they're fit to store.
+    fn build(&self, filename: &str) -> Result<(String, bool), weldr::Error> {
+        let mut scratch = self.scratch.lock().unwrap();
+        let part_name = weldr::parse(filename, self.resolver, &mut scratch)?;
+
+        let mut flattener = Flattener {
+            output: String::new(),
+            clip: true,
+        };
+        flattener.header(&scratch, &part_name);
+        let contents = &mut flattener.output;
+        contents.push_str(MAGIC);
+        contents.push('\n');
+
+        // anything that isn't a library file (say, a placeholder) can't be validated later
+        let mut complete = true;
+        let mut dependencies = HashSet::new();
+        for name in subfiles(&scratch, &part_name) {
+            match self.resolver.locate(&name) {
+                Some(location) => {
+                    dependencies.insert((location.path, location.modified));
+                }
+                None => {
+                    complete = false;
+                    self.resolver.missing_beneath(&name, &part_name);
+                }
+            }
+        }
+        for (path, modified) in dependencies {
+            let since_epoch = modified.duration_since(UNIX_EPOCH).unwrap_or_default();
+            writeln!(
+                contents,
+                "{DEPENDENCY}{}.{:09} {}",
+                since_epoch.as_secs(),
+                since_epoch.subsec_nanos(),
+                path.display(),
+            )
+            .unwrap();
+        }
+
+        contents.push_str("0 BFC CERTIFY CCW\n");
+        let mut ctx = GeometryContext::new();
+        ctx.transform = Mat4::IDENTITY;
+        flattener.flatten(&scratch, &part_name, ctx);
+        flattener.output.push_str(END);
+        flattener.output.push('\n');
+
+        Ok((flattener.output, complete))
+    }
+}
+
+impl FileRefResolver for PartCache<'_> {
+    fn resolve<P: AsRef<Path>>(&self, filename: P) -> Result<Vec<u8>, weldr::ResolveError> {
+        let name = filename.as_ref().to_string_lossy();
+        // custom parts keep their structure, for writing them back out with the model
+        let location = self.resolver.locate(&name);
+        let Some(location) = location.filter(|l| l.is_part && !l.is_custom) else {
+            return self.resolver.resolve(filename);
+        };
+
+        let entry = self.entry_path(&name, &location.path);
+        if let Some(contents) = read_valid(&entry) {
+            return Ok(contents);
+        }
+
+        let (contents, complete) = self
+            .build(&name)
+            .map_err(|e| weldr::ResolveError::new(name.clone().into_owned(), e))?;
+
+        if complete {
+            // failing to store an entry just means building it again next time
+            let _ = store(&entry, &contents);
+        }
+
+        Ok(contents.into_bytes())
+    }
+}
+
+/// Writes a cache entry alongside where it goes and then moves it into place,
+/// so other processes sharing the cache never see it half-written.
+fn store(entry: &Path, contents: &str) -> std::io::Result<()> {
+    if let Some(dir) = entry.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let temp = entry.with_extension(format!("{}.tmp", std::process::id()));
+    std::fs::write(&temp, contents)?;
+    std::fs::rename(&temp, entry).inspect_err(|_| {
+        let _ = std::fs::remove_file(&temp);
+    })
+}
+
+/// Reads a cache entry, if it was written in full
+/// and none of the files it was built from have changed since.
+fn read_valid(entry: &Path) -> Option<Vec<u8>> {
+    let contents = std::fs::read_to_string(entry).ok()?;
+    if contents.lines().next_back() != Some(END) {
+        return None;
+    }
+    let mut lines = contents.lines();
+    lines.find(|l| *l == MAGIC)?;
+
+    for line in lines.map_while(|l| l.strip_prefix(DEPENDENCY)) {
+        let (modified, path) = line.split_once(' ')?;
+        let (secs, nanos) = modified.split_once('.')?;
+        let modified = UNIX_EPOCH + Duration::new(secs.parse().ok()?, nanos.parse().ok()?);
+        let current = Path::new(path).metadata().and_then(|m| m.modified()).ok()?;
+        if current != modified {
+            return None;
+        }
+    }
+
+    Some(contents.into_bytes())
+}
+
+/// Every file reachable from `name`, including itself.
+fn subfiles(source_map: &SourceMap, name: &str) -> Vec<String> {
+    let mut seen = HashSet::from([name.to_owned()]);
+    let mut queue = vec![name.to_owned()];
+    let mut output = vec![];
+    while let Some(name) = queue.pop() {
+        if let Some(file) = source_map.get(&name) {
+            for cmd in &file.cmds {
+                if let Command::SubFileRef(sfrc) = cmd
+                    && seen.insert(sfrc.file.clone())
+                {
+                    queue.push(sfrc.file.clone());
+                }
+            }
+        }
+        output.push(name);
+    }
+    output
+}
+
+/// Whether anything reachable from `name` has lines in the edge color.
+fn has_edges(source_map: &SourceMap, name: &str) -> bool {
+    subfiles(source_map, name).iter().any(|name| {
+        source_map.get(name).is_some_and(|file| {
+            file.cmds.iter().any(|cmd| match cmd {
+                Command::Line(l) => l.color == EDGE_COLOR,
+                Command::OptLine(l) => l.color == EDGE_COLOR,
+                _ => false,
+            })
+        })
+    })
+}
+
+/// Writes a part and everything it references out as one file,
+/// with windings normalized to CCW and uncertified geometry fenced off with `BFC NOCLIP`.
+///
+/// Subfiles given a color of their own that have edges are kept as references instead.
+struct Flattener {
+    output: String,
+    clip: bool,
+}
+
+impl Flattener {
+    /// Copies the part's own header (minus BFC), so the flattened file still describes itself.
+    fn header(&mut self, source_map: &SourceMap, name: &str) {
+        let Some(file) = source_map.get(name) else {
+            return;
+        };
+        for cmd in &file.cmds {
+            match cmd {
+                Command::Comment(c) if c.text.starts_with("BFC") => {}
+                Command::Comment(c) => writeln!(self.output, "0 {}", c.text).unwrap(),
+                Command::Category(c) => {
+                    writeln!(self.output, "0 !CATEGORY {}", c.category).unwrap()
+                }
+                Command::Keywords(k) => {
+                    writeln!(self.output, "0 !KEYWORDS {}", k.keywords.join(", ")).unwrap()
+                }
+                _ => break,
+            }
+        }
+    }
+
+    fn flatten(&mut self, source_map: &SourceMap, name: &str, ctx: GeometryContext) {
+        let Some(file) = source_map.get(name) else {
+            return;
+        };
+
+        let mut bfc = Bfc::new(file, &ctx);
+
+        for cmd in &file.cmds {
+            bfc.apply(cmd);
+            let reversed = bfc.winding() == Winding::Cw;
+
+            match cmd {
+                Command::SubFileRef(sfrc) => {
+                    let child = bfc.child(&ctx, sfrc);
+                    // edges are drawn in the edge color of the color they inherit, which only
+                    // a reference can pass on, so one that sets a color isn't merged if it has any
+                    if child.color != CURRENT_COLOR && has_edges(source_map, &sfrc.file) {
+                        self.set_clip(child.clip);
+                        if child.inverted {
+                            self.output.push_str("0 BFC INVERTNEXT\n");
+                        }
+                        write_reference(&mut self.output, child.color, child.transform, &sfrc.file);
+                    } else {
+                        self.flatten(source_map, &sfrc.file, child);
+                    }
+                }
+                Command::Line(l) => {
+                    let color = new_color(ctx.color, l.color);
+                    self.write(2, color, &ctx.project(l.vertices));
+                }
+                Command::OptLine(l) => {
+                    let color = new_color(ctx.color, l.color);
+                    let [a, b] = ctx.project(l.vertices);
+                    let [c, d] = ctx.project(l.control_points);
+                    self.write(5, color, &[a, b, c, d]);
+                }
+                Command::Triangle(t) => {
+                    self.set_clip(bfc.clipping());
+                    let mut vertices = ctx.project(t.vertices);
+                    if reversed {
+                        vertices.reverse();
+                    }
+                    self.write(3, new_color(ctx.color, t.color), &vertices);
+                }
+                Command::Quad(q) => {
+                    self.set_clip(bfc.clipping());
+                    let mut vertices = ctx.project(q.vertices);
+                    if reversed {
+                        vertices.reverse();
+                    }
+                    self.write(4, new_color(ctx.color, q.color), &vertices);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn set_clip(&mut self, clip: bool) {
+        if clip != self.clip {
+            self.clip = clip;
+            let cmd = if clip { "CLIP" } else { "NOCLIP" };
+            writeln!(self.output, "0 BFC {cmd}").unwrap();
+        }
+    }
+
+    fn write(&mut self, line_type: u8, color: u32, vertices: &[weldr::Vec3]) {
+        write!(self.output, "{line_type} {color}").unwrap();
+        for v in vertices {
+            write!(self.output, " {} {} {}", v.x, v.y, v.z).unwrap();
+        }
+        self.output.push('\n');
+    }
+}
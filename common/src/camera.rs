@@ -0,0 +1,106 @@
+use glam::{Mat4, Vec3};
+
+/// Where a model is viewed from.
+///
+/// Angles are in degrees. With both at zero, the model is seen from the front:
+/// LDraw's +X points right, -Y points up and +Z points away from the viewer.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Camera {
+    /// How far the camera is raised above the horizon, looking down onto the model.
+    pub latitude: f32,
+    /// How far the camera is moved around the model, towards its right side.
+    pub longitude: f32,
+    pub projection: Projection,
+    /// The point the camera looks at, which ends up at the origin.
+    pub target: Vec3,
+    /// Output units per LDU at the target.
+    pub scale: f32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Projection {
+    Orthographic,
+    Perspective {
+        /// The vertical field of view, in degrees.
+        fov: f32,
+        /// How far the eye is from the target, in LDU.
+        /// Anything at or behind the eye isn't drawn.
+        distance: f32,
+    },
+}
+
+impl Default for Camera {
+    /// The isometric view used by LDraw instructions.
+    fn default() -> Self {
+        Self {
+            latitude: 30.0_f32.to_radians().tan().asin().to_degrees(),
+            longitude: 45.0,
+            ..Self::front()
+        }
+    }
+}
+
+impl Camera {
+    pub fn front() -> Self {
+        Self {
+            latitude: 0.0,
+            longitude: 0.0,
+            projection: Projection::Orthographic,
+            target: Vec3::ZERO,
+            scale: 1.0,
+        }
+    }
+
+    pub fn top() -> Self {
+        Self {
+            latitude: 90.0,
+            ..Self::front()
+        }
+    }
+
+    /// The model's right side.
+    pub fn side() -> Self {
+        Self {
+            longitude: 90.0,
+            ..Self::front()
+        }
+    }
+
+    /// Looks up a view by name: `front`, `top`, `side` or `iso`.
+    pub fn named(name: &str) -> Option<Self> {
+        match name {
+            "front" => Some(Self::front()),
+            "top" => Some(Self::top()),
+            "side" => Some(Self::side()),
+            "iso" => Some(Self::default()),
+            _ => None,
+        }
+    }
+
+    /// Moves model space into view space: x right, y down, and z away from the viewer.
+    pub fn view(&self) -> Mat4 {
+        Mat4::from_rotation_x(self.latitude.to_radians())
+            * Mat4::from_rotation_y(self.longitude.to_radians())
+            * Mat4::from_translation(-self.target)
+    }
+
+    /// Moves view space into output space.
+    ///
+    /// Orthographic views are just scaled. Perspective views are scaled so that the field of view
+    /// spans `scale` output units either side of the target, and keep depth in the same order.
+    pub fn projection(&self) -> Mat4 {
+        let s = self.scale;
+        match self.projection {
+            Projection::Orthographic => Mat4::from_scale(Vec3::splat(s)),
+            Projection::Perspective { fov, distance } => {
+                let f = 1.0 / (fov.to_radians() / 2.0).tan();
+                Mat4::from_cols(
+                    [s * f, 0.0, 0.0, 0.0].into(),
+                    [0.0, s * f, 0.0, 0.0].into(),
+                    [0.0, 0.0, s * distance, 1.0].into(),
+                    [0.0, 0.0, 0.0, distance].into(),
+                )
+            }
+        }
+    }
+}
@@ -0,0 +1,208 @@
+use std::collections::HashMap;
+
+use crate::resolver::{Resolver, normalize};
+
+/// Descriptions of every part in the libraries, as given by their headers.
+#[derive(Debug, Default)]
+pub struct Catalog {
+    entries: HashMap<String, PartInfo>,
+}
+
+/// What a part's header says about it.
+#[derive(Debug, Clone, Default)]
+pub struct PartInfo {
+    /// Normalized filename, relative to the `parts` directory.
+    pub filename: String,
+    pub title: String,
+    pub name: Option<String>,
+    pub author: Option<String>,
+    /// The file type from `!LDRAW_ORG`.
+    pub kind: Option<PartKind>,
+    pub unofficial: bool,
+    /// Qualifiers from `!LDRAW_ORG`, such as `Alias` or `Physical_Colour`.
+    pub qualifiers: Vec<String>,
+    /// The explicit `!CATEGORY`, if any. See [`PartInfo::category`].
+    pub explicit_category: Option<String>,
+    pub keywords: Vec<String>,
+    /// The part this one is an alias of.
+    pub alias_of: Option<String>,
+    /// The part this one was renamed to.
+    pub moved_to: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PartKind {
+    Part,
+    Subpart,
+    Primitive,
+    Shortcut,
+    Model,
+    Configuration,
+    Other(String),
+}
+
+impl Catalog {
+    /// Reads the header of every part in the resolver's `parts` directories.
+    pub fn build(resolver: &Resolver) -> Self {
+        let entries = resolver
+            .part_names()
+            .into_iter()
+            // which leaves out textures, also kept under `parts`
+            .filter(|filename| filename.ends_with(".dat"))
+            .filter_map(|filename| {
+                let header = resolver.header(&filename)?.ok()?;
+                Some((filename.clone(), PartInfo::from_header(filename, &header)))
+            })
+            .collect();
+        Self { entries }
+    }
+
+    pub fn get(&self, filename: &str) -> Option<&PartInfo> {
+        self.entries.get(&normalize(filename))
+    }
+
+    pub fn description(&self, filename: &str) -> Option<&str> {
+        Some(&self.get(filename)?.title)
+    }
+
+    pub fn category(&self, filename: &str) -> Option<&str> {
+        self.get(filename)?.category()
+    }
+
+    /// Follows `~Moved to` renames and aliases to the part that should actually be used.
+    pub fn canonical<'a>(&'a self, filename: &'a str) -> &'a str {
+        let mut current = filename;
+        // bounded, in case of a cycle
+        for _ in 0..16 {
+            let Some(info) = self.get(current) else {
+                break;
+            };
+            match info.moved_to.as_deref().or(info.alias_of.as_deref()) {
+                Some(next) => current = next,
+                None => break,
+            }
+        }
+        current
+    }
+
+    /// Finds parts whose filename, title, category or keywords contain `query`, ignoring case.
+    pub fn search(&self, query: &str) -> Vec<&PartInfo> {
+        let query = query.to_lowercase();
+        let mut output = self
+            .entries
+            .values()
+            .filter(|info| {
+                let mut fields = [info.filename.as_str(), &info.title]
+                    .into_iter()
+                    .chain(info.category())
+                    .chain(info.keywords.iter().map(String::as_str));
+                fields.any(|field| field.to_lowercase().contains(&query))
+            })
+            .collect::<Vec<_>>();
+        output.sort_by(|a, b| a.filename.cmp(&b.filename));
+        output
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &PartInfo> {
+        self.entries.values()
+    }
+}
+
+impl PartInfo {
+    pub fn from_header(filename: String, contents: &[u8]) -> Self {
+        let text = String::from_utf8_lossy(contents);
+        let mut info = Self {
+            filename,
+            ..Default::default()
+        };
+
+        let mut lines = text.lines().map(str::trim).filter(|l| !l.is_empty());
+        if let Some(title) = lines.next().and_then(|l| l.strip_prefix('0')) {
+            info.title = title.trim().to_owned();
+        }
+
+        for line in lines {
+            let Some(meta) = line.strip_prefix('0') else {
+                // the header's over once geometry starts, but aliases and moved parts
+                // consist of a single reference to their target
+                if info.alias_of.is_none() && info.moved_to.is_none() {
+                    let target = subfile_name(line).map(|name| normalize(&name));
+                    if info.title.starts_with("~Moved to") {
+                        info.moved_to = target;
+                    } else if info.qualifiers.iter().any(|q| q == "Alias") {
+                        info.alias_of = target;
+                    }
+                }
+                break;
+            };
+
+            let meta = meta.trim();
+            let (keyword, rest) = meta.split_once(char::is_whitespace).unwrap_or((meta, ""));
+            let rest = rest.trim();
+            match keyword {
+                "Name:" => info.name = Some(rest.to_owned()),
+                "Author:" => info.author = Some(rest.to_owned()),
+                "!CATEGORY" => info.explicit_category = Some(rest.to_owned()),
+                "!KEYWORDS" => info.keywords.extend(
+                    rest.split(',')
+                        .map(str::trim)
+                        .filter(|k| !k.is_empty())
+                        .map(str::to_owned),
+                ),
+                "!LDRAW_ORG" => {
+                    let mut words = rest.split_whitespace();
+                    let Some(kind) = words.next() else { continue };
+                    let (kind, unofficial) = match kind.strip_prefix("Unofficial_") {
+                        Some(kind) => (kind, true),
+                        None => (kind, false),
+                    };
+                    info.kind = Some(PartKind::parse(kind));
+                    info.unofficial = unofficial;
+                    info.qualifiers = words
+                        .take_while(|w| !matches!(*w, "ORIGINAL" | "UPDATE"))
+                        .map(str::to_owned)
+                        .collect();
+                }
+                _ => {}
+            }
+        }
+
+        info
+    }
+
+    /// The explicit `!CATEGORY`, or else the first word of the title, as the LDraw spec prescribes.
+    pub fn category(&self) -> Option<&str> {
+        if let Some(category) = &self.explicit_category {
+            return Some(category);
+        }
+        self.title
+            .trim_start_matches(['~', '_', '=', '|'])
+            .split_whitespace()
+            .next()
+    }
+}
+
+impl PartKind {
+    fn parse(s: &str) -> Self {
+        match s {
+            "Part" => Self::Part,
+            "Subpart" => Self::Subpart,
+            "Primitive" | "8_Primitive" | "48_Primitive" => Self::Primitive,
+            "Shortcut" => Self::Shortcut,
+            "Model" => Self::Model,
+            "Configuration" => Self::Configuration,
+            other => Self::Other(other.to_owned()),
+        }
+    }
+}
+
+/// The filename of a type 1 line, which may contain spaces.
+fn subfile_name(line: &str) -> Option<String> {
+    let mut rest = line.strip_prefix('1')?;
+    // color, position, and the 3x3 matrix
+    for _ in 0..13 {
+        rest = rest.trim_start();
+        rest = &rest[rest.find(char::is_whitespace)?..];
+    }
+    Some(rest.trim().to_owned())
+}
@@ -0,0 +1,523 @@
+use std::collections::HashMap;
+
+use weldr::{Command, Mat4, SourceMap, Vec3};
+
+use crate::{
+    Poly, Primitive,
+    hierarchy::is_part,
+    ldr::{
+        Ancestors, Bfc, CURRENT_COLOR, ColorCode, GeometryContext, LineColor, TraverseError,
+        Winding, faces_away, get_model, is_silhouette, line_color, new_color, reference_number,
+    },
+    steps::Placement,
+};
+
+/// A part's geometry in its own space, from its own commands and those of its subfiles.
+#[derive(Debug, Clone, Default)]
+pub struct PartGeometry {
+    pub polygons: Vec<Polygon>,
+    pub lines: Vec<([Vec3; 2], LineColor)>,
+    /// Optional lines, with their control points.
+    pub opt_lines: Vec<([Vec3; 2], [Vec3; 2], LineColor)>,
+}
+
+/// A triangle or quad wound counter-clockwise, seen from the front.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Polygon {
+    pub vertices: Poly,
+    /// The polygon's own color, which is the part's for most.
+    pub color: ColorCode,
+    /// Whether it's visible from behind too, because BFC clipping was off where it was drawn.
+    pub double_sided: bool,
+}
+
+impl Polygon {
+    /// The polygon split into triangles, wound the same way.
+    pub fn triangles(&self) -> impl Iterator<Item = [Vec3; 3]> + use<> {
+        let (first, second) = match self.vertices {
+            Poly::Tri(vertices) => (vertices, None),
+            Poly::Quad([a, b, c, d]) => ([a, b, c], Some([c, d, a])),
+        };
+        std::iter::once(first).chain(second)
+    }
+
+    /// The triangles in each winding they can be seen in, for an instance that's `inverted` or
+    /// has clipping off as given: once if the polygon is one-sided there, or both ways round if not.
+    pub fn windings(&self, inverted: bool, clip: bool) -> impl Iterator<Item = [Vec3; 3]> + use<> {
+        let double_sided = self.double_sided || !clip;
+        self.triangles().flat_map(move |[a, b, c]| {
+            let [front, back] = [[a, b, c], [c, b, a]];
+            let [front, back] = if inverted {
+                [back, front]
+            } else {
+                [front, back]
+            };
+            std::iter::once(front).chain(double_sided.then_some(back))
+        })
+    }
+
+    /// The unit normal of the front face.
+    pub fn normal(&self) -> Vec3 {
+        let [a, b, c] = self.triangles().next().unwrap();
+        (b - a).cross(c - a).normalize_or_zero()
+    }
+}
+
+impl PartGeometry {
+    /// Collects a part's geometry, with every polygon wound counter-clockwise as the BFC statements
+    /// along the way dictate.
+    pub fn build(source_map: &SourceMap, part_name: &str) -> Result<Self, TraverseError> {
+        let mut geometry = Self::default();
+        let ctx = GeometryContext {
+            transform: Mat4::IDENTITY,
+            projection: Mat4::IDENTITY,
+            ..GeometryContext::new()
+        };
+        geometry.add(source_map, part_name, ctx, &mut Ancestors::default())?;
+        Ok(geometry)
+    }
+
+    fn add<'a>(
+        &mut self,
+        source_map: &'a SourceMap,
+        name: &'a str,
+        ctx: GeometryContext,
+        ancestors: &mut Ancestors<'a>,
+    ) -> Result<(), TraverseError> {
+        let file = get_model(source_map, name)?;
+        ancestors.enter(name)?;
+        let mut bfc = Bfc::new(file, &ctx);
+
+        for (i, cmd) in file.cmds.iter().enumerate() {
+            bfc.apply(cmd);
+            match cmd {
+                Command::SubFileRef(sfrc) => {
+                    let child = bfc.child(&ctx, sfrc);
+                    self.add(source_map, &sfrc.file, child, ancestors)
+                        .map_err(|e| e.referenced_by(name, reference_number(file, i)))?;
+                }
+                Command::Line(l) => {
+                    let color = line_color(ctx.color, l.color);
+                    self.lines.push((ctx.project(l.vertices), color));
+                }
+                Command::OptLine(l) => {
+                    let [vertices, control_points] =
+                        [l.vertices, l.control_points].map(|x| ctx.project(x));
+                    let color = line_color(ctx.color, l.color);
+                    self.opt_lines.push((vertices, control_points, color));
+                }
+                Command::Triangle(t) => {
+                    let vertices = Poly::Tri(ctx.project(t.vertices));
+                    self.push_polygon(&bfc, new_color(ctx.color, t.color), vertices);
+                }
+                Command::Quad(q) => {
+                    let vertices = Poly::Quad(ctx.project(q.vertices));
+                    self.push_polygon(&bfc, new_color(ctx.color, q.color), vertices);
+                }
+                _ => {}
+            }
+        }
+
+        ancestors.leave();
+        Ok(())
+    }
+
+    fn push_polygon(&mut self, bfc: &Bfc, color: ColorCode, mut vertices: Poly) {
+        if bfc.winding() == Winding::Cw {
+            vertices.as_mut_slice().reverse();
+        }
+        self.polygons.push(Polygon {
+            vertices,
+            color,
+            double_sided: !bfc.clipping(),
+        });
+    }
+
+    /// Draws an instance of the part, placed and colored by `ctx`, the way [`traverse`] would.
+    ///
+    /// [`traverse`]: crate::ldr::traverse
+    pub fn instantiate(&self, ctx: &GeometryContext, output: &mut Vec<Primitive>) {
+        let winding = if ctx.inverted ^ (ctx.transform.determinant() < 0.0) {
+            Winding::Cw
+        } else {
+            Winding::Ccw
+        };
+
+        for polygon in &self.polygons {
+            let vertices = match polygon.vertices {
+                Poly::Tri(vertices) => ctx.project_visible(vertices).map(Poly::Tri),
+                Poly::Quad(vertices) => ctx.project_visible(vertices).map(Poly::Quad),
+            };
+            let Some(vertices) = vertices else {
+                continue;
+            };
+            let clipping = ctx.clip && !polygon.double_sided;
+            if ctx.cull && clipping && faces_away(vertices.as_slice(), winding) {
+                continue;
+            }
+            let color = new_color(ctx.color, polygon.color);
+            output.push(Primitive::Polygon(vertices, color));
+        }
+        for &(vertices, color) in &self.lines {
+            if let Some(vertices) = ctx.project_visible(vertices) {
+                output.push(Primitive::Line(vertices, color.inherit(ctx.color)));
+            }
+        }
+        for &(vertices, control_points, color) in &self.opt_lines {
+            if let Some(vertices) = ctx.project_visible(vertices)
+                && let Some(control_points) = ctx.project_visible(control_points)
+                && is_silhouette(vertices, control_points)
+            {
+                output.push(Primitive::Line(vertices, color.inherit(ctx.color)));
+            }
+        }
+    }
+
+    /// The polygons' triangles for an instance of the part, in each winding they can be seen in,
+    /// grouped by color in order of first appearance.
+    ///
+    /// Windings follow the instance's `INVERTNEXT` and clipping, but not its transform.
+    pub fn triangles_by_color(&self, instance: &Placement) -> Vec<(ColorCode, Vec<[Vec3; 3]>)> {
+        let mut output: Vec<(ColorCode, Vec<[Vec3; 3]>)> = vec![];
+        for polygon in &self.polygons {
+            let polygon_color = new_color(instance.color, polygon.color);
+            let i = match output.iter().position(|(c, _)| *c == polygon_color) {
+                Some(i) => i,
+                None => {
+                    output.push((polygon_color, vec![]));
+                    output.len() - 1
+                }
+            };
+            output[i]
+                .1
+                .extend(polygon.windings(instance.inverted, instance.clip));
+        }
+        output
+    }
+
+    /// Welds the polygons' triangles into an indexed mesh with smooth normals.
+    ///
+    /// Normals are averaged across every edge shared by two triangles, except where an edge line
+    /// runs along it, which LDraw uses to mark hard creases, or where the triangles meet at
+    /// more than [`CREASE_ANGLE`] regardless.
+    ///
+    /// Windings follow the instance's `INVERTNEXT` and clipping, as in
+    /// [`triangles_by_color`](Self::triangles_by_color).
+    pub fn smooth_mesh(&self, instance: &Placement) -> SmoothMesh {
+        let faces = self
+            .polygons
+            .iter()
+            .flat_map(|p| {
+                p.windings(instance.inverted, instance.clip)
+                    .map(move |vertices| (vertices, p.color))
+            })
+            .collect::<Vec<_>>();
+
+        let mut welder = Welder::default();
+        let corners = faces
+            .iter()
+            .map(|(vertices, _)| vertices.map(|v| welder.weld(v)))
+            .collect::<Vec<_>>();
+        // unnormalized, so bigger faces count for more when they're summed
+        let face_normals = faces
+            .iter()
+            .map(|([a, b, c], _)| (*b - *a).cross(*c - *a))
+            .collect::<Vec<_>>();
+
+        let creases = Creases::new(self.lines.iter().map(|(line, _)| *line));
+        let is_crease = |a: u32, b: u32| {
+            creases.contains(welder.positions[a as usize], welder.positions[b as usize])
+        };
+
+        // triangles by the edges they have, in either direction
+        let mut edges: HashMap<(u32, u32), Vec<usize>> = HashMap::new();
+        for (face, &[a, b, c]) in corners.iter().enumerate() {
+            for (u, v) in [(a, b), (b, c), (c, a)] {
+                edges.entry((u.min(v), u.max(v))).or_default().push(face);
+            }
+        }
+
+        // corners (face * 3 + index) sharing a normal
+        let mut groups = UnionFind::new(corners.len() * 3);
+        let corner = |face: usize, vertex: u32| {
+            let i = corners[face].iter().position(|&v| v == vertex).unwrap();
+            face * 3 + i
+        };
+        for (&(u, v), faces) in &edges {
+            if faces.len() < 2 || is_crease(u, v) {
+                continue;
+            }
+            for (i, &f) in faces.iter().enumerate() {
+                for &g in &faces[i + 1..] {
+                    let [nf, ng] = [f, g].map(|face| face_normals[face].normalize_or_zero());
+                    if nf.dot(ng) < CREASE_ANGLE.to_radians().cos() {
+                        continue;
+                    }
+                    groups.union(corner(f, u), corner(g, u));
+                    groups.union(corner(f, v), corner(g, v));
+                }
+            }
+        }
+
+        let mut sums: HashMap<usize, Vec3> = HashMap::new();
+        for (i, normal) in face_normals.iter().enumerate() {
+            for k in 0..3 {
+                *sums.entry(groups.find(i * 3 + k)).or_default() += *normal;
+            }
+        }
+
+        let mut mesh = SmoothMesh::default();
+        let mut indices_by_group: HashMap<usize, u32> = HashMap::new();
+        for (face, vertices) in corners.iter().enumerate() {
+            for (k, &vertex) in vertices.iter().enumerate() {
+                let group = groups.find(face * 3 + k);
+                let index = *indices_by_group.entry(group).or_insert_with(|| {
+                    mesh.positions.push(welder.positions[vertex as usize]);
+                    mesh.normals.push(sums[&group].normalize_or_zero());
+                    mesh.positions.len() as u32 - 1
+                });
+                mesh.indices.push(index);
+            }
+            mesh.colors.push(faces[face].1);
+        }
+        mesh
+    }
+}
+
+/// How far apart two vertices can be and still be welded together, squared, in LDraw units.
+const WELD_DISTANCE: f32 = 1e-6;
+
+/// The angle in degrees beyond which two faces are never smoothed together,
+/// even without an edge line between them.
+pub const CREASE_ANGLE: f32 = 60.0;
+
+/// Triangles as an indexed mesh, with a normal for each vertex.
+#[derive(Debug, Clone, Default)]
+pub struct SmoothMesh {
+    pub positions: Vec<Vec3>,
+    pub normals: Vec<Vec3>,
+    /// Three per triangle, each wound counter-clockwise.
+    pub indices: Vec<u32>,
+    /// The color of each triangle, as in [`Polygon::color`].
+    pub colors: Vec<ColorCode>,
+}
+
+/// Gives vertices the same index when they're in the same place, give or take float error.
+#[derive(Default)]
+struct Welder {
+    positions: Vec<Vec3>,
+    indices: HashMap<[i64; 3], u32>,
+}
+
+impl Welder {
+    fn weld(&mut self, v: Vec3) -> u32 {
+        // a grid coarse enough to absorb error, which can still split near-identical vertices
+        // that land either side of a cell boundary; those just don't get smoothed together
+        let key = (v / WELD_DISTANCE.sqrt())
+            .round()
+            .to_array()
+            .map(|n| n as i64);
+        *self.indices.entry(key).or_insert_with(|| {
+            self.positions.push(v);
+            self.positions.len() as u32 - 1
+        })
+    }
+}
+
+/// Edge lines, bucketed by the grid cells they pass through.
+struct Creases {
+    lines: Vec<[Vec3; 2]>,
+    cells: HashMap<[i32; 3], Vec<usize>>,
+}
+
+impl Creases {
+    const CELL_SIZE: f32 = 4.0;
+
+    fn new(lines: impl Iterator<Item = [Vec3; 2]>) -> Self {
+        let mut creases = Self {
+            lines: lines.collect(),
+            cells: HashMap::new(),
+        };
+        for (i, &[a, b]) in creases.lines.iter().enumerate() {
+            // sampled closely enough that every point on the line is in a cell next to a sample
+            let samples = ((b - a).length() / (Self::CELL_SIZE / 2.0)).ceil() as usize;
+            for k in 0..=samples {
+                let cell = Self::cell(a.lerp(b, k as f32 / samples.max(1) as f32));
+                let lines = creases.cells.entry(cell).or_default();
+                if lines.last() != Some(&i) {
+                    lines.push(i);
+                }
+            }
+        }
+        creases
+    }
+
+    fn cell(p: Vec3) -> [i32; 3] {
+        (p / Self::CELL_SIZE).floor().to_array().map(|n| n as i32)
+    }
+
+    /// Whether an edge runs along a line. Lines often span several triangle edges, or a triangle
+    /// edge several lines, so this checks the edge's midpoint lies on a line parallel to it.
+    fn contains(&self, a: Vec3, b: Vec3) -> bool {
+        let mid = (a + b) / 2.0;
+        let direction = (b - a).normalize_or_zero();
+        let [x, y, z] = Self::cell(mid);
+        let neighbours = (-1..=1).flat_map(|dx| {
+            (-1..=1).flat_map(move |dy| (-1..=1).map(move |dz| [x + dx, y + dy, z + dz]))
+        });
+        neighbours
+            .filter_map(|cell| self.cells.get(&cell))
+            .flatten()
+            .any(|&i| {
+                let [start, end] = self.lines[i];
+                let d = end - start;
+                let t = (mid - start).dot(d) / d.length_squared();
+                (0.0..=1.0).contains(&t)
+                    && (start + d * t).distance_squared(mid) < WELD_DISTANCE
+                    && d.normalize_or_zero().cross(direction).length_squared() < WELD_DISTANCE
+            })
+    }
+}
+
+struct UnionFind {
+    parents: Vec<usize>,
+}
+
+impl UnionFind {
+    fn new(len: usize) -> Self {
+        Self {
+            parents: (0..len).collect(),
+        }
+    }
+
+    fn find(&mut self, mut i: usize) -> usize {
+        while self.parents[i] != i {
+            self.parents[i] = self.parents[self.parents[i]];
+            i = self.parents[i];
+        }
+        i
+    }
+
+    fn union(&mut self, a: usize, b: usize) {
+        let (a, b) = (self.find(a), self.find(b));
+        self.parents[a] = b;
+    }
+}
+
+/// Each part's geometry, built the first time it's needed and shared by every instance after.
+#[derive(Debug, Default)]
+pub struct GeometryCache {
+    parts: HashMap<String, PartGeometry>,
+}
+
+impl GeometryCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(
+        &mut self,
+        source_map: &SourceMap,
+        part_name: &str,
+    ) -> Result<&PartGeometry, TraverseError> {
+        if !self.parts.contains_key(part_name) {
+            let geometry = PartGeometry::build(source_map, part_name)?;
+            self.parts.insert(part_name.to_owned(), geometry);
+        }
+        Ok(&self.parts[part_name])
+    }
+
+    /// Draws a model into `output` like [`traverse`], but building each distinct part only once
+    /// and then placing copies of it.
+    ///
+    /// [`traverse`]: crate::ldr::traverse
+    pub fn draw(
+        &mut self,
+        source_map: &SourceMap,
+        model_name: &str,
+        ctx: &GeometryContext,
+        output: &mut Vec<Primitive>,
+    ) -> Result<(), TraverseError> {
+        for instance in part_instances(source_map, model_name)? {
+            let instance_ctx = GeometryContext {
+                transform: ctx.transform * instance.transform,
+                color: new_color(ctx.color, instance.color),
+                inverted: ctx.inverted ^ instance.inverted,
+                clip: ctx.clip && instance.clip,
+                ..ctx.clone()
+            };
+            self.get(source_map, &instance.file)?
+                .instantiate(&instance_ctx, output);
+        }
+        Ok(())
+    }
+}
+
+/// Every part in a model, across all instances of its submodels, placed in the model's space
+/// and with inherited colors and BFC state resolved.
+///
+/// A model that's a part itself is its own only instance.
+pub fn part_instances(
+    source_map: &SourceMap,
+    model_name: &str,
+) -> Result<Vec<Placement>, TraverseError> {
+    let mut output = vec![];
+    if is_part(source_map, model_name) {
+        get_model(source_map, model_name)?;
+        output.push(Placement {
+            file: model_name.to_owned(),
+            color: CURRENT_COLOR,
+            transform: Mat4::IDENTITY,
+            inverted: false,
+            clip: true,
+        });
+    } else {
+        let ctx = GeometryContext {
+            transform: Mat4::IDENTITY,
+            ..GeometryContext::new()
+        };
+        add_instances(
+            source_map,
+            model_name,
+            ctx,
+            &mut output,
+            &mut Ancestors::default(),
+        )?;
+    }
+    Ok(output)
+}
+
+fn add_instances<'a>(
+    source_map: &'a SourceMap,
+    model_name: &'a str,
+    ctx: GeometryContext,
+    output: &mut Vec<Placement>,
+    ancestors: &mut Ancestors<'a>,
+) -> Result<(), TraverseError> {
+    let model = get_model(source_map, model_name)?;
+    ancestors.enter(model_name)?;
+    let mut bfc = Bfc::new(model, &ctx);
+
+    for (i, cmd) in model.cmds.iter().enumerate() {
+        bfc.apply(cmd);
+        let Command::SubFileRef(sfrc) = cmd else {
+            continue;
+        };
+        let child = bfc.child(&ctx, sfrc);
+        if is_part(source_map, &sfrc.file) {
+            output.push(Placement {
+                file: sfrc.file.clone(),
+                color: child.color,
+                transform: child.transform,
+                inverted: child.inverted,
+                clip: child.clip,
+            });
+        } else {
+            add_instances(source_map, &sfrc.file, child, output, ancestors)
+                .map_err(|e| e.referenced_by(model_name, reference_number(model, i)))?;
+        }
+    }
+
+    ancestors.leave();
+    Ok(())
+}
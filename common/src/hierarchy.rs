@@ -0,0 +1,148 @@
+use std::collections::HashMap;
+
+use weldr::{Command, SourceMap};
+
+use crate::{
+    ldr::{Ancestors, Bfc, GeometryContext, TraverseError, get_model, reference_number},
+    steps::Placement,
+};
+
+/// The submodels of a model, and how they're put together.
+#[derive(Debug, Clone)]
+pub struct Hierarchy {
+    pub root: String,
+    /// Every submodel reachable from the root, including itself, by name.
+    pub submodels: HashMap<String, Submodel>,
+    /// Submodel names, each after all of its children.
+    order: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Submodel {
+    pub name: String,
+    /// Parts referenced directly by this submodel.
+    pub parts: Vec<Placement>,
+    /// Submodels referenced directly by this submodel.
+    pub children: Vec<Placement>,
+    /// Where this submodel is referenced, by parent name. Empty for the root.
+    pub instances: Vec<(String, Placement)>,
+    /// How many copies of this submodel the whole model contains.
+    pub count: usize,
+}
+
+impl Hierarchy {
+    pub fn build(source_map: &SourceMap, root: &str) -> Result<Self, TraverseError> {
+        let mut submodels = HashMap::new();
+        let mut order = vec![];
+        let mut ancestors = Ancestors::default();
+        visit(source_map, root, &mut submodels, &mut order, &mut ancestors)?;
+
+        // going backwards, every parent comes before its children, so its count is final by then
+        submodels.get_mut(root).unwrap().count = 1;
+        for name in order.iter().rev() {
+            let count = submodels[name].count;
+            let children = submodels[name].children.clone();
+            for child in children {
+                submodels.get_mut(&child.file).unwrap().count += count;
+            }
+        }
+
+        Ok(Self {
+            root: root.to_owned(),
+            submodels,
+            order,
+        })
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Submodel> {
+        self.submodels.get(name)
+    }
+
+    /// Submodels in an order where each one comes after all of its children.
+    pub fn bottom_up(&self) -> impl Iterator<Item = &Submodel> {
+        self.order.iter().map(|name| &self.submodels[name])
+    }
+}
+
+impl Submodel {
+    /// How many times each child submodel is referenced directly, in order of first reference.
+    pub fn child_counts(&self) -> Vec<(&str, usize)> {
+        let mut output: Vec<(&str, usize)> = vec![];
+        for child in &self.children {
+            match output.iter_mut().find(|(name, _)| *name == child.file) {
+                Some((_, n)) => *n += 1,
+                None => output.push((&child.file, 1)),
+            }
+        }
+        output
+    }
+}
+
+/// Adds a submodel and everything below it, recording them in post-order.
+fn visit<'a>(
+    source_map: &'a SourceMap,
+    name: &'a str,
+    submodels: &mut HashMap<String, Submodel>,
+    order: &mut Vec<String>,
+    ancestors: &mut Ancestors<'a>,
+) -> Result<(), TraverseError> {
+    // only inserted once all of its children are, so anything still in progress is a cycle
+    if submodels.contains_key(name) {
+        return Ok(());
+    }
+
+    let model = get_model(source_map, name)?;
+    ancestors.enter(name)?;
+    let mut submodel = Submodel {
+        name: name.to_owned(),
+        parts: vec![],
+        children: vec![],
+        instances: vec![],
+        count: 0,
+    };
+    // with the index of the command referencing them, for errors
+    let mut references = vec![];
+    let mut bfc = Bfc::new(model, &GeometryContext::new());
+    for (i, cmd) in model.cmds.iter().enumerate() {
+        bfc.apply(cmd);
+        let Command::SubFileRef(sfrc) = cmd else {
+            continue;
+        };
+        let placement = Placement::new(&mut bfc, sfrc);
+        if is_part(source_map, &sfrc.file) {
+            submodel.parts.push(placement);
+        } else {
+            submodel.children.push(placement.clone());
+            references.push((i, &sfrc.file, placement));
+        }
+    }
+
+    for (i, file, child) in references {
+        visit(source_map, file, submodels, order, ancestors)
+            .map_err(|e| e.referenced_by(name, reference_number(model, i)))?;
+        let child_submodel = submodels.get_mut(file).unwrap();
+        child_submodel.instances.push((name.to_owned(), child));
+    }
+
+    submodels.insert(name.to_owned(), submodel);
+    order.push(name.to_owned());
+    ancestors.leave();
+    Ok(())
+}
+
+/// Whether a file is a part rather than a submodel: one with an `!LDRAW_ORG` type other than
+/// `Model`, or with any geometry of its own. Files that aren't in the source map count as parts.
+pub fn is_part(source_map: &SourceMap, name: &str) -> bool {
+    let Some(file) = source_map.get(name) else {
+        return true;
+    };
+    file.cmds.iter().any(|cmd| match cmd {
+        Command::Comment(c) => c
+            .text
+            .strip_prefix("!LDRAW_ORG")
+            .and_then(|rest| rest.split_whitespace().next())
+            .is_some_and(|kind| !kind.ends_with("Model")),
+        Command::Line(_) | Command::Triangle(_) | Command::Quad(_) | Command::OptLine(_) => true,
+        _ => false,
+    })
+}
@@ -1,46 +1,225 @@
-use std::collections::HashMap;
+use std::{borrow::Cow, collections::HashMap};
 
-use crate::{Point, Poly, Primitive};
+use crate::{Point, Poly, Primitive, camera::Camera, material::Material, resolver::Resolver};
 use slab::Slab;
-use weldr::{ColourCmd, Command, Mat4, SourceMap, Vec3};
+use weldr::{Color, ColourCmd, Command, Mat4, SourceFile, SourceMap, Vec3};
 
 pub fn traverse(
     source_map: &SourceMap,
     model_name: &str,
     ctx: GeometryContext,
     output: &mut Vec<Primitive>,
-) {
-    let Some(model) = source_map.get(model_name) else {
-        panic!("{model_name}");
-    };
+) -> Result<(), TraverseError> {
+    traverse_file(
+        source_map,
+        model_name,
+        ctx,
+        output,
+        &mut Ancestors::default(),
+    )
+}
+
+fn traverse_file<'a>(
+    source_map: &'a SourceMap,
+    model_name: &'a str,
+    ctx: GeometryContext,
+    output: &mut Vec<Primitive>,
+    ancestors: &mut Ancestors<'a>,
+) -> Result<(), TraverseError> {
+    let model = get_model(source_map, model_name)?;
+    ancestors.enter(model_name)?;
+    let mut bfc = Bfc::new(model, &ctx);
 
-    for cmd in &model.cmds {
+    for (i, cmd) in model.cmds.iter().enumerate() {
+        bfc.apply(cmd);
         match cmd {
-            Command::Comment(..) => {}
             Command::SubFileRef(sfrc) => {
-                traverse(source_map, &sfrc.file, ctx.child(sfrc, false), output);
+                let child = bfc.child(&ctx, sfrc);
+                traverse_file(source_map, &sfrc.file, child, output, ancestors)
+                    .map_err(|e| e.referenced_by(model_name, reference_number(model, i)))?;
             }
             Command::Line(line) => {
-                output.push(Primitive::Line(ctx.project(line.vertices)));
+                if let Some(vertices) = ctx.project_visible(line.vertices) {
+                    let color = line_color(ctx.color, line.color);
+                    output.push(Primitive::Line(vertices, color));
+                }
+            }
+            Command::OptLine(line) => {
+                if let Some(vertices) = ctx.project_visible(line.vertices)
+                    && let Some(control_points) = ctx.project_visible(line.control_points)
+                    && is_silhouette(vertices, control_points)
+                {
+                    let color = line_color(ctx.color, line.color);
+                    output.push(Primitive::Line(vertices, color));
+                }
             }
             Command::Triangle(t) => {
-                let color = new_color(ctx.color, t.color);
-                let poly = Poly::Tri(ctx.project(t.vertices));
-                output.push(Primitive::Polygon(poly, color));
+                if let Some(vertices) = ctx.project_visible(t.vertices)
+                    && !(ctx.cull && bfc.is_back_face(&vertices))
+                {
+                    let color = new_color(ctx.color, t.color);
+                    output.push(Primitive::Polygon(Poly::Tri(vertices), color));
+                }
             }
             Command::Quad(q) => {
-                let color = new_color(ctx.color, q.color);
-                let poly = Poly::Quad(ctx.project(q.vertices));
-                output.push(Primitive::Polygon(poly, color));
+                if let Some(vertices) = ctx.project_visible(q.vertices)
+                    && !(ctx.cull && bfc.is_back_face(&vertices))
+                {
+                    let color = new_color(ctx.color, q.color);
+                    output.push(Primitive::Polygon(Poly::Quad(vertices), color));
+                }
             }
             _ => {}
         }
     }
+
+    ancestors.leave();
+    Ok(())
+}
+
+/// Whether an optional line should be drawn, which is when both of its control points
+/// fall on the same side of it in the view plane.
+pub(crate) fn is_silhouette([a, b]: [Point; 2], control_points: [Point; 2]) -> bool {
+    let side = |p: Point| (b.x - a.x) * (p.y - a.y) - (b.y - a.y) * (p.x - a.x);
+    let [c, d] = control_points.map(side);
+    c * d > 0.0
+}
+
+pub fn get_model<'a>(
+    source_map: &'a SourceMap,
+    model_name: &str,
+) -> Result<&'a SourceFile, TraverseError> {
+    source_map.get(model_name).ok_or_else(|| TraverseError {
+        file: model_name.to_owned(),
+        kind: TraverseErrorKind::Missing,
+        chain: vec![],
+    })
+}
+
+/// The files currently being traversed, outermost first, to catch any that reference themselves
+/// before they overflow the stack.
+#[derive(Default)]
+pub(crate) struct Ancestors<'a>(Vec<&'a str>);
+
+impl<'a> Ancestors<'a> {
+    /// Starts traversing a file, unless it's already being traversed further up.
+    pub fn enter(&mut self, name: &'a str) -> Result<(), TraverseError> {
+        if self.0.contains(&name) {
+            return Err(TraverseError {
+                file: name.to_owned(),
+                kind: TraverseErrorKind::Cycle,
+                chain: vec![],
+            });
+        }
+        self.0.push(name);
+        Ok(())
+    }
+
+    /// Finishes traversing the file entered last.
+    pub fn leave(&mut self) {
+        self.0.pop();
+    }
+}
+
+/// A model couldn't be traversed.
+#[derive(Debug, Clone)]
+pub struct TraverseError {
+    pub file: String,
+    pub kind: TraverseErrorKind,
+    /// The references that led to it, outermost first.
+    pub chain: Vec<Reference>,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraverseErrorKind {
+    /// The file was referenced but isn't in the [`SourceMap`].
+    Missing,
+    /// The file references itself, directly or through others.
+    Cycle,
+}
+
+/// One file referencing another.
+#[derive(Debug, Clone)]
+pub struct Reference {
+    pub model: String,
+    /// Which of the model's subfile references it is, counting from 1.
+    pub reference: usize,
+    pub file: String,
+}
+
+/// Which subfile reference the command at `index` is among a file's commands, counting from 1.
+///
+/// weldr doesn't keep line numbers, so this is the closest thing that can be found in the file.
+pub(crate) fn reference_number(file: &SourceFile, index: usize) -> usize {
+    file.cmds[..=index]
+        .iter()
+        .filter(|cmd| matches!(cmd, Command::SubFileRef(_)))
+        .count()
+}
+
+impl TraverseError {
+    /// Records that the file at the start of the chain was referenced from `model`,
+    /// by its subfile reference numbered `reference`.
+    pub fn referenced_by(mut self, model: &str, reference: usize) -> Self {
+        let file = match self.chain.first() {
+            Some(reference) => reference.model.clone(),
+            None => self.file.clone(),
+        };
+        let reference = Reference {
+            model: model.to_owned(),
+            reference,
+            file,
+        };
+        self.chain.insert(0, reference);
+        self
+    }
+}
+
+impl std::fmt::Display for TraverseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.kind {
+            TraverseErrorKind::Missing => write!(f, "{} not found", self.file)?,
+            TraverseErrorKind::Cycle => write!(f, "{} references itself", self.file)?,
+        }
+        for reference in self.chain.iter().rev() {
+            let Reference {
+                model,
+                reference,
+                file,
+            } = reference;
+            write!(
+                f,
+                "\n    {file} referenced by {model} (subfile reference #{reference})"
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for TraverseError {}
+
 pub type ColorCode = u32;
 // Special color code that "inherits" the existing color.
-const CURRENT_COLOR: ColorCode = 16;
+pub(crate) const CURRENT_COLOR: ColorCode = 16;
+// Special color code for the edge color of the existing color.
+pub(crate) const EDGE_COLOR: ColorCode = 24;
+
+/// The color of a line, which may be the edge color of another.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum LineColor {
+    Main(ColorCode),
+    Edge(ColorCode),
+}
+
+impl LineColor {
+    /// The color once drawn somewhere whose current color is `current`.
+    pub fn inherit(self, current: ColorCode) -> Self {
+        match self {
+            Self::Main(code) => Self::Main(new_color(current, code)),
+            Self::Edge(code) => Self::Edge(new_color(current, code)),
+        }
+    }
+}
 
 #[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
 pub enum Winding {
@@ -59,38 +238,204 @@ impl std::ops::Not for Winding {
     }
 }
 
+/// Back-face culling state while walking one file, following the LDraw BFC spec.
+#[derive(Debug, Clone)]
+pub struct Bfc {
+    /// Whether the file has a `BFC CERTIFY` before its first drawing command.
+    certified: bool,
+    /// Whether the reference to this file had clipping enabled.
+    parent_clip: bool,
+    clip: bool,
+    winding: Winding,
+    /// Whether the file is mirrored on its way into output space, by its transform or `INVERTNEXT`.
+    inverted: bool,
+    invert_next: bool,
+}
+
+impl Bfc {
+    pub fn new(file: &SourceFile, ctx: &GeometryContext) -> Self {
+        let mut certified = false;
+        for cmd in &file.cmds {
+            match cmd {
+                Command::Comment(c) => match bfc_words(&c.text).next() {
+                    Some("CERTIFY") => certified = true,
+                    Some("NOCERTIFY") => certified = false,
+                    _ => continue,
+                },
+                Command::SubFileRef(_)
+                | Command::Line(_)
+                | Command::Triangle(_)
+                | Command::Quad(_)
+                | Command::OptLine(_) => {}
+                _ => continue,
+            }
+            break;
+        }
+
+        Self {
+            certified,
+            parent_clip: ctx.clip,
+            clip: true,
+            winding: Winding::Ccw,
+            inverted: ctx.inverted ^ (ctx.transform.determinant() < 0.0),
+            invert_next: false,
+        }
+    }
+
+    /// Updates the state for a command, which should then be drawn with it.
+    pub fn apply(&mut self, cmd: &Command) {
+        match cmd {
+            Command::Comment(c) => {
+                // CERTIFY, CLIP and the winding can be combined in any order
+                for word in bfc_words(&c.text) {
+                    match word {
+                        "CW" => self.winding = Winding::Cw,
+                        "CCW" => self.winding = Winding::Ccw,
+                        "CLIP" => self.clip = true,
+                        "NOCLIP" => self.clip = false,
+                        "INVERTNEXT" => self.invert_next = true,
+                        _ => {}
+                    }
+                }
+            }
+            Command::SubFileRef(_) => {}
+            // INVERTNEXT only applies to a subfile reference immediately after it
+            Command::Line(_) | Command::Triangle(_) | Command::Quad(_) | Command::OptLine(_) => {
+                self.invert_next = false;
+            }
+            _ => {}
+        }
+    }
+
+    /// The context for a subfile referenced at this point, which takes up any `INVERTNEXT`.
+    pub fn child(
+        &mut self,
+        ctx: &GeometryContext,
+        subfile: &weldr::SubFileRefCmd,
+    ) -> GeometryContext {
+        let mut child = ctx.child(subfile, std::mem::take(&mut self.invert_next));
+        // uncertified files ignore BFC meta-commands, so they pass their parent's state along
+        child.clip = self.parent_clip && (self.clip || !self.certified);
+        child
+    }
+
+    pub fn certified(&self) -> bool {
+        self.certified
+    }
+
+    /// Whether polygons at this point are one-sided, and may be culled.
+    /// That's only the case if this file is certified, and neither it nor any certified file
+    /// referencing it has turned clipping off.
+    pub fn clipping(&self) -> bool {
+        self.certified && self.parent_clip && self.clip
+    }
+
+    /// The winding of the file's polygons once they're in output space.
+    pub fn winding(&self) -> Winding {
+        if self.inverted {
+            !self.winding
+        } else {
+            self.winding
+        }
+    }
+
+    /// Whether a polygon in output space faces away from the viewer.
+    /// Double-sided polygons never do.
+    pub fn is_back_face(&self, vertices: &[Point]) -> bool {
+        self.clipping() && faces_away(vertices, self.winding())
+    }
+}
+
+/// Whether a polygon in output space with the given winding faces away from the viewer.
+pub(crate) fn faces_away(vertices: &[Point], winding: Winding) -> bool {
+    // twice the signed area in the view plane; y points down, so this is negative when
+    // the polygon appears counter-clockwise
+    let area: f32 = (0..vertices.len())
+        .map(|i| {
+            let (a, b) = (vertices[i], vertices[(i + 1) % vertices.len()]);
+            a.x * b.y - b.x * a.y
+        })
+        .sum();
+    match winding {
+        Winding::Ccw => area > 0.0,
+        Winding::Cw => area < 0.0,
+    }
+}
+
+/// The words of a BFC meta-command, after `BFC`.
+fn bfc_words(comment: &str) -> impl Iterator<Item = &str> {
+    let rest = comment
+        .trim_start()
+        .strip_prefix("BFC ")
+        .unwrap_or_default();
+    rest.split_whitespace()
+}
+
 #[derive(Clone)]
 pub struct GeometryContext {
+    /// From the current file's space into view space.
     pub transform: Mat4,
+    /// From view space into output space, applied after `transform`.
+    pub projection: Mat4,
     pub color: ColorCode,
     pub inverted: bool,
+    /// Whether to drop polygons that face away from the viewer.
+    pub cull: bool,
+    /// Whether clipping was enabled where this file was referenced. See [`Bfc::clipping`].
+    pub clip: bool,
 }
 
 impl GeometryContext {
+    /// The default isometric view.
     pub fn new() -> Self {
-        let alpha = 30.0_f32.to_radians().tan().asin();
-        let beta = 45.0_f32.to_radians();
-        let transform = Mat4::from_rotation_x(alpha) * Mat4::from_rotation_y(beta);
+        Self::from_camera(&Camera::default())
+    }
+
+    pub fn from_camera(camera: &Camera) -> Self {
         Self {
-            transform,
+            transform: camera.view(),
+            projection: camera.projection(),
             color: CURRENT_COLOR,
             inverted: false,
+            cull: false,
+            clip: true,
         }
     }
 
     pub fn child(&self, subfile: &weldr::SubFileRefCmd, invert: bool) -> Self {
         Self {
             transform: self.transform * subfile.matrix(),
+            projection: self.projection,
             color: new_color(self.color, subfile.color),
             inverted: invert ^ self.inverted,
+            cull: self.cull,
+            clip: self.clip,
         }
     }
 
     pub fn project<const N: usize>(&self, vertices: [Vec3; N]) -> [Point; N] {
-        vertices.map(|v| self.transform.transform_point3(v))
+        vertices.map(|v| {
+            let v = self.transform.transform_point3(v);
+            self.projection.project_point3(v)
+        })
+    }
+
+    /// Like [`project`](Self::project), but `None` if any of the vertices are too close to
+    /// a perspective camera, or behind it, to end up anywhere sensible in output space.
+    pub fn project_visible<const N: usize>(&self, vertices: [Vec3; N]) -> Option<[Point; N]> {
+        let clip_space =
+            vertices.map(|v| self.projection * self.transform.transform_point3(v).extend(1.0));
+        clip_space
+            .iter()
+            .all(|v| v.w > NEAR_PLANE)
+            .then(|| clip_space.map(|v| v.truncate() / v.w))
     }
 }
 
+/// How far in front of a perspective camera's eye, in LDU, anything has to be to get drawn.
+/// Orthographic projections leave everything at 1.
+const NEAR_PLANE: f32 = 0.1;
+
 pub fn new_color(current: ColorCode, new: ColorCode) -> ColorCode {
     if new == CURRENT_COLOR {
         current
@@ -99,34 +444,390 @@ pub fn new_color(current: ColorCode, new: ColorCode) -> ColorCode {
     }
 }
 
+pub fn line_color(current: ColorCode, new: ColorCode) -> LineColor {
+    if new == EDGE_COLOR {
+        LineColor::Edge(current)
+    } else {
+        LineColor::Main(new_color(current, new))
+    }
+}
+
 #[derive(Default)]
 pub struct ColorMap {
     codes: HashMap<ColorCode, usize>,
     names: HashMap<String, usize>,
     values: Slab<ColourCmd>,
+    /// BrickLink's codes for colors, where Stud.io's definitions give them.
+    bricklink: HashMap<ColorCode, u32>,
+}
+
+/// Which of Stud.io's color values to use.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum Palette {
+    #[default]
+    Rgb,
+    /// The colors Stud.io uses for instructions meant for print,
+    /// when their page setup asks for the CMYK color table.
+    Cmyk,
 }
 
 impl ColorMap {
     pub fn load(path: &str) -> Result<Self, Box<dyn std::error::Error>> {
-        let data = std::fs::read(path)?;
-        let cmds = weldr::parse_raw(&data)?;
-        let mut map = ColorMap::default();
+        Self::from_ldconfig(&std::fs::read(path)?)
+    }
 
-        for cmd in cmds {
+    pub fn from_ldconfig(data: &[u8]) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut map = ColorMap::default();
+        for cmd in weldr::parse_raw(data)? {
             let Command::Colour(c) = cmd else { continue };
-            let key = map.values.insert(c.clone());
-            map.codes.insert(c.code, key);
-            map.names.insert(c.name, key);
+            map.insert(c);
         }
+        Ok(map)
+    }
 
+    /// Loads the libraries' `LDConfig.ldr`, overlaid with Stud.io's definitions if it's installed.
+    pub fn from_resolver(
+        resolver: &Resolver,
+        palette: Palette,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let ldconfig = resolver
+            .library_file("LDConfig.ldr")
+            .ok_or("LDConfig.ldr not found in any library")??;
+        let mut map = Self::from_ldconfig(&ldconfig)?;
+        if let Some(path) = resolver.studio_color_definitions() {
+            map.load_studio(path, palette)?;
+        }
         Ok(map)
     }
 
-    // pub fn by_name(&self, name: &str) -> &ColourCmd {
-    //     &self.values[self.names[name]]
-    // }
+    /// Adds a color, replacing any existing one with the same code.
+    pub fn insert(&mut self, color: ColourCmd) {
+        let key = match self.codes.get(&color.code) {
+            Some(&key) => {
+                let old = std::mem::replace(&mut self.values[key], color.clone());
+                self.names.remove(&old.name);
+                key
+            }
+            None => self.values.insert(color.clone()),
+        };
+        self.codes.insert(color.code, key);
+        self.names.insert(color.name, key);
+    }
+
+    /// Adds the `!COLOUR` definitions made within a model and its subfiles.
+    pub fn add_model_colors(&mut self, source_map: &SourceMap, model_name: &str) {
+        let mut seen = std::collections::HashSet::new();
+        let mut queue = vec![model_name];
+        while let Some(name) = queue.pop() {
+            let Some(file) = source_map.get(name) else {
+                continue;
+            };
+            for cmd in &file.cmds {
+                match cmd {
+                    Command::Colour(c) => self.insert(c.clone()),
+                    Command::SubFileRef(sfrc) if seen.insert(&sfrc.file) => queue.push(&sfrc.file),
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    pub fn load_studio(
+        &mut self,
+        path: impl AsRef<std::path::Path>,
+        palette: Palette,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let data = std::fs::read(path)?;
+        self.add_studio(&String::from_utf8_lossy(&data), palette)
+    }
+
+    /// Adds colors from Stud.io's `StudioColorDefinition.txt`, or a CSV export of it.
+    ///
+    /// Columns are found by their headers, so extra or reordered columns don't matter.
+    /// Existing colors keep their edge color and finish.
+    pub fn add_studio(
+        &mut self,
+        table: &str,
+        palette: Palette,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut lines = table.lines().map(|l| l.trim_start_matches('\u{FEFF}'));
+        let header = lines.next().ok_or("empty color definitions")?;
+        let separator = if header.contains('\t') { '\t' } else { ',' };
+        let columns = split_fields(header, separator);
+        let column = |names: &[&str]| {
+            columns
+                .iter()
+                .position(|c| names.iter().any(|n| c.eq_ignore_ascii_case(n)))
+        };
+
+        let code_column = column(&["Studio Color Code", "LDraw Color Code"])
+            .ok_or("color definitions have no color code column")?;
+        let name_column = column(&["Studio Color Name", "LDraw Color Name"]);
+        let rgb_column =
+            column(&["RGB value", "RGB"]).ok_or("color definitions have no RGB column")?;
+        let alpha_column = column(&["Alpha"]);
+        let bricklink_column = column(&["BL Color Code"]);
+        let cmyk_column = columns
+            .iter()
+            .position(|c| c.to_ascii_uppercase().contains("CMYK"));
+
+        for line in lines.filter(|l| !l.trim().is_empty()) {
+            let fields = split_fields(line, separator);
+            let field = |i: Option<usize>| {
+                i.and_then(|i| fields.get(i))
+                    .map(String::as_str)
+                    .filter(|f| !f.is_empty())
+            };
+
+            let Some(Ok(code)) = field(Some(code_column)).map(|f| f.parse::<ColorCode>()) else {
+                continue;
+            };
+            let cmyk = field(cmyk_column).filter(|_| palette == Palette::Cmyk);
+            let Some(value) = cmyk
+                .and_then(parse_studio_color)
+                .or_else(|| field(Some(rgb_column)).and_then(parse_studio_color))
+            else {
+                continue;
+            };
+            let alpha = field(alpha_column)
+                .and_then(|f| f.parse::<f32>().ok())
+                // Stud.io gives alpha as a fraction, LDConfig as a byte
+                .map(|a| if a <= 1.0 { a * 255.0 } else { a }.round() as u8)
+                .filter(|&a| a < 0xFF);
+
+            if let Some(Ok(bricklink)) = field(bricklink_column).map(|f| f.parse()) {
+                self.bricklink.insert(code, bricklink);
+            }
+
+            let existing = self.codes.get(&code).map(|&key| &self.values[key]);
+            let name = match field(name_column) {
+                Some(name) => name.replace(' ', "_"),
+                None => existing.map_or_else(|| code.to_string(), |c| c.name.clone()),
+            };
+            let color = ColourCmd {
+                name,
+                code,
+                value,
+                edge: existing.map_or_else(|| default_edge(value), |c| c.edge),
+                alpha,
+                luminance: existing.and_then(|c| c.luminance),
+                finish: existing.and_then(|c| c.finish.clone()),
+            };
+            self.insert(color);
+        }
+
+        Ok(())
+    }
+
+    /// BrickLink's code for a color, if known.
+    pub fn bricklink_code(&self, code: ColorCode) -> Option<u32> {
+        self.bricklink.get(&code).copied()
+    }
+
+    pub fn by_name(&self, name: &str) -> Option<&ColourCmd> {
+        Some(&self.values[*self.names.get(name)?])
+    }
+
+    /// Looks up a color, or decodes it if it's a direct color.
+    pub fn by_code(&self, code: ColorCode) -> Result<Cow<'_, ColourCmd>, UnknownColor> {
+        if let Some(key) = self.codes.get(&code) {
+            return Ok(Cow::Borrowed(&self.values[*key]));
+        }
+        direct_color(code).map(Cow::Owned).ok_or(UnknownColor(code))
+    }
+
+    pub fn material(&self, code: ColorCode) -> Result<Material, UnknownColor> {
+        Ok(Material::new(&*self.by_code(code)?))
+    }
+
+    pub fn line_color(&self, color: LineColor) -> Result<weldr::Color, UnknownColor> {
+        match color {
+            LineColor::Main(code) => Ok(self.by_code(code)?.value),
+            LineColor::Edge(code) => Ok(self.by_code(code)?.edge),
+        }
+    }
+}
+
+/// Splits a line of a table into trimmed fields, where a field in double quotes may contain
+/// the separator, and `""` within one stands for a quote.
+fn split_fields(line: &str, separator: char) -> Vec<String> {
+    let mut fields = vec![];
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            '"' if quoted || field.trim().is_empty() => quoted = !quoted,
+            c if c == separator && !quoted => fields.push(std::mem::take(&mut field)),
+            c => field.push(c),
+        }
+    }
+    fields.push(field);
+    fields.iter().map(|f| f.trim().to_owned()).collect()
+}
+
+/// Parses a color from Stud.io's definitions, either `#RRGGBB` or CMYK percentages.
+fn parse_studio_color(field: &str) -> Option<Color> {
+    if let Some(hex) = field.strip_prefix('#') {
+        let rgb = u32::from_str_radix(hex, 16).ok()?;
+        return Some(Color::new((rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8));
+    }
+
+    let cmyk = field
+        .split([',', ' ', ';'])
+        .filter(|f| !f.is_empty())
+        .map(|f| f.parse::<f32>().ok().map(|n| n / 100.0))
+        .collect::<Option<Vec<_>>>()?;
+    let &[c, m, y, k] = cmyk.as_slice() else {
+        return None;
+    };
+    let channel = |n: f32| (255.0 * (1.0 - n) * (1.0 - k)).round() as u8;
+    Some(Color::new(channel(c), channel(m), channel(y)))
+}
+
+/// An edge color for a color LDConfig doesn't know, which is black unless that wouldn't show.
+fn default_edge(value: Color) -> Color {
+    if value.red.max(value.green).max(value.blue) < 0x40 {
+        Color::new(0x59, 0x59, 0x59)
+    } else {
+        Color::new(0, 0, 0)
+    }
+}
+
+/// Decodes a color given directly as RGB, rather than as an entry in LDConfig.
+///
+/// These are `0x2RRGGBB` for opaque colors and `0x3RRGGBB` for transparent ones,
+/// or `0x4RGBRGB` for a dither of two colors, which is shown as their average.
+/// `0x5RGBxxx` and `0x6xxxRGB` dither one color with transparency.
+fn direct_color(code: ColorCode) -> Option<ColourCmd> {
+    let nibble = |shift: u32| ((code >> shift) & 0xF) as u8 * 0x11;
+    let byte = |shift: u32| (code >> shift) as u8;
+
+    let (value, alpha) = match code >> 24 {
+        0x2 => (Color::new(byte(16), byte(8), byte(0)), None),
+        0x3 => (Color::new(byte(16), byte(8), byte(0)), Some(128)),
+        0x4 => {
+            let average = |a: u8, b: u8| ((a as u16 + b as u16) / 2) as u8;
+            let value = Color::new(
+                average(nibble(20), nibble(8)),
+                average(nibble(16), nibble(4)),
+                average(nibble(12), nibble(0)),
+            );
+            (value, None)
+        }
+        0x5 => (Color::new(nibble(20), nibble(16), nibble(12)), Some(128)),
+        0x6 => (Color::new(nibble(8), nibble(4), nibble(0)), Some(128)),
+        _ => return None,
+    };
+
+    Some(ColourCmd {
+        name: format!("0x{code:07X}"),
+        code,
+        value,
+        edge: Color::new(0, 0, 0),
+        alpha,
+        luminance: None,
+        finish: None,
+    })
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct UnknownColor(pub ColorCode);
+
+impl std::fmt::Display for UnknownColor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "unknown color code {}", self.0)
+    }
+}
+
+impl std::error::Error for UnknownColor {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn direct_opaque_and_transparent() {
+        let opaque = direct_color(0x2FF8000).unwrap();
+        assert_eq!(opaque.value, Color::new(0xFF, 0x80, 0x00));
+        assert_eq!(opaque.alpha, None);
+        assert_eq!(opaque.name, "0x2FF8000");
+
+        let transparent = direct_color(0x3123456).unwrap();
+        assert_eq!(transparent.value, Color::new(0x12, 0x34, 0x56));
+        assert_eq!(transparent.alpha, Some(128));
+    }
+
+    #[test]
+    fn direct_dithers() {
+        // F00 and 00F, averaged
+        let dither = direct_color(0x4F0000F).unwrap();
+        assert_eq!(dither.value, Color::new(0x7F, 0x00, 0x7F));
+        assert_eq!(dither.alpha, None);
+
+        // the first color with transparency, then the second
+        let first = direct_color(0x5F80123).unwrap();
+        assert_eq!(first.value, Color::new(0xFF, 0x88, 0x00));
+        assert_eq!(first.alpha, Some(128));
+        let second = direct_color(0x6123F80).unwrap();
+        assert_eq!(second.value, Color::new(0xFF, 0x88, 0x00));
+        assert_eq!(second.alpha, Some(128));
+    }
+
+    #[test]
+    fn direct_rejects_library_codes() {
+        assert!(direct_color(4).is_none());
+        assert!(direct_color(0x1FFFFFF).is_none());
+        assert!(direct_color(0x7FFFFFF).is_none());
+    }
+
+    #[test]
+    fn studio_hex() {
+        assert_eq!(
+            parse_studio_color("#1B2A34"),
+            Some(Color::new(0x1B, 0x2A, 0x34))
+        );
+        assert_eq!(parse_studio_color("#GGGGGG"), None);
+    }
+
+    #[test]
+    fn studio_cmyk() {
+        assert_eq!(
+            parse_studio_color("0,0,0,0"),
+            Some(Color::new(255, 255, 255))
+        );
+        assert_eq!(parse_studio_color("0 0 0 100"), Some(Color::new(0, 0, 0)));
+        assert_eq!(
+            parse_studio_color("100; 0; 100; 0"),
+            Some(Color::new(0, 255, 0))
+        );
+        assert_eq!(
+            parse_studio_color("0,50,100,50"),
+            Some(Color::new(128, 64, 0))
+        );
+    }
+
+    #[test]
+    fn studio_cmyk_needs_four_numbers() {
+        assert_eq!(parse_studio_color("0,0,0"), None);
+        assert_eq!(parse_studio_color("0,0,0,0,0"), None);
+        assert_eq!(parse_studio_color("0,0,zero,0"), None);
+        assert_eq!(parse_studio_color(""), None);
+    }
 
-    pub fn by_code(&self, code: ColorCode) -> &ColourCmd {
-        &self.values[*self.codes.get(&code).unwrap_or(&0)]
+    #[test]
+    fn studio_csv_quotes() {
+        let table = "Studio Color Code,Studio Color Name,RGB value,BL Color Code,CMYK\n\
+                     4,\"Red, \"\"Bright\"\"\",#C91A09,5,\"0,50,100,50\"\n";
+        let mut colors = ColorMap::default();
+        colors.add_studio(table, Palette::Cmyk).unwrap();
+        let red = colors.by_code(4).unwrap();
+        assert_eq!(red.name, "Red,_\"Bright\"");
+        assert_eq!(red.value, Color::new(128, 64, 0));
+        assert_eq!(colors.bricklink_code(4), Some(5));
     }
 }
@@ -2,17 +2,29 @@ use std::fs::File;
 use std::io::Read;
 use zip::ZipArchive;
 
+pub mod bom;
+pub mod cache;
+pub mod camera;
+pub mod catalog;
+pub mod geometry;
+pub mod hierarchy;
 pub mod ldr;
+pub mod material;
 pub mod resolver;
+pub mod steps;
+pub mod writer;
 
-use ldr::ColorCode;
+use ldr::{ColorCode, LineColor};
 
 pub type Result<T, E = Box<dyn std::error::Error>> = std::result::Result<T, E>;
 
+/// Stud.io encrypts some entries of its `.io` archives with this.
+const IO_PASSWORD: &[u8] = b"soho0909";
+
 pub fn read_model_ins(path: impl AsRef<std::path::Path>) -> Result<String, zip::result::ZipError> {
     let f = File::open(path)?;
     let mut zip = ZipArchive::new(f)?;
-    let mut ins = zip.by_name_decrypt("model.ins", b"soho0909")?;
+    let mut ins = zip.by_name_decrypt("model.ins", IO_PASSWORD)?;
     let mut buf = String::new();
     ins.read_to_string(&mut buf)?;
     Ok(buf)
@@ -20,7 +32,7 @@ pub fn read_model_ins(path: impl AsRef<std::path::Path>) -> Result<String, zip::
 
 pub type Point = glam::Vec3;
 
-#[derive(Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub enum Poly {
     Tri([Point; 3]),
     Quad([Point; 4]),
@@ -43,21 +55,21 @@ impl Poly {
 
 #[derive(Copy, Clone)]
 pub enum Primitive {
-    Line([Point; 2]),
+    Line([Point; 2], LineColor),
     Polygon(Poly, ColorCode),
 }
 
 impl Primitive {
     pub fn as_slice(&self) -> &[Point] {
         match self {
-            Self::Line(l) => l,
+            Self::Line(l, _) => l,
             Self::Polygon(p, _) => p.as_slice(),
         }
     }
 
     pub fn as_mut_slice(&mut self) -> &mut [Point] {
         match self {
-            Self::Line(l) => l,
+            Self::Line(l, _) => l,
             Self::Polygon(p, _) => p.as_mut_slice(),
         }
     }
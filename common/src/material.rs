@@ -0,0 +1,103 @@
+use weldr::{Color, ColorFinish, ColourCmd, MaterialFinish};
+
+/// How a color should be shaded, as described by its LDConfig entry.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Material {
+    pub color: Color,
+    /// Opacity, from 0 to 255.
+    pub alpha: u8,
+    /// How much light the color gives off, from 0 to 255.
+    pub luminance: u8,
+    pub finish: Finish,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Finish {
+    Plain,
+    Chrome,
+    Pearlescent,
+    Rubber,
+    MatteMetallic,
+    Metal,
+    /// Flakes of another color, like the translucent glitter colors.
+    Glitter {
+        color: Color,
+        fraction: f32,
+    },
+    /// Specks of another color, like the speckled opaque colors.
+    Speckle {
+        color: Color,
+        fraction: f32,
+    },
+}
+
+impl Material {
+    pub fn new(colour: &ColourCmd) -> Self {
+        let finish = match &colour.finish {
+            None => Finish::Plain,
+            Some(ColorFinish::Chrome) => Finish::Chrome,
+            Some(ColorFinish::Pearlescent) => Finish::Pearlescent,
+            Some(ColorFinish::Rubber) => Finish::Rubber,
+            Some(ColorFinish::MatteMetallic) => Finish::MatteMetallic,
+            Some(ColorFinish::Metal) => Finish::Metal,
+            Some(ColorFinish::Material(MaterialFinish::Glitter(g))) => Finish::Glitter {
+                color: g.value,
+                fraction: g.fraction,
+            },
+            Some(ColorFinish::Material(MaterialFinish::Speckle(s))) => Finish::Speckle {
+                color: s.value,
+                fraction: s.fraction,
+            },
+            Some(ColorFinish::Material(MaterialFinish::Other(_))) => Finish::Plain,
+        };
+
+        Self {
+            color: colour.value,
+            alpha: colour.alpha.unwrap_or(0xFF),
+            luminance: colour.luminance.unwrap_or(0),
+            finish,
+        }
+    }
+
+    pub fn is_translucent(&self) -> bool {
+        self.alpha < 0xFF
+    }
+
+    /// The color as seen from a distance, with any glitter or speckles mixed in.
+    pub fn average_color(&self) -> Color {
+        let (Finish::Glitter { color, fraction } | Finish::Speckle { color, fraction }) =
+            self.finish
+        else {
+            return self.color;
+        };
+        let mix = |a: u8, b: u8| (a as f32 + (b as f32 - a as f32) * fraction).round() as u8;
+        Color::new(
+            mix(self.color.red, color.red),
+            mix(self.color.green, color.green),
+            mix(self.color.blue, color.blue),
+        )
+    }
+
+    /// How metallic the surface is, from 0 to 1, for physically based shading.
+    pub fn metallic(&self) -> f32 {
+        match self.finish {
+            Finish::Chrome | Finish::Metal => 1.0,
+            Finish::MatteMetallic => 0.8,
+            Finish::Pearlescent => 0.4,
+            Finish::Speckle { fraction, .. } => fraction,
+            Finish::Plain | Finish::Rubber | Finish::Glitter { .. } => 0.0,
+        }
+    }
+
+    /// How rough the surface is, from 0 to 1, for physically based shading.
+    pub fn roughness(&self) -> f32 {
+        match self.finish {
+            Finish::Chrome => 0.05,
+            Finish::Metal => 0.2,
+            Finish::Pearlescent => 0.25,
+            Finish::MatteMetallic => 0.55,
+            Finish::Rubber => 0.9,
+            Finish::Plain | Finish::Glitter { .. } | Finish::Speckle { .. } => 0.3,
+        }
+    }
+}
@@ -1,45 +1,710 @@
 use std::{
+    collections::{HashMap, HashSet},
     fs::File,
-    io::Read,
+    hash::{Hash, Hasher},
+    io::{BufRead, BufReader, Read},
     path::{Path, PathBuf},
+    sync::Mutex,
+    time::SystemTime,
 };
 
-use weldr::FileRefResolver;
-use zip::ZipArchive;
+use weldr::{Command, FileRefResolver, SourceMap};
+use zip::{
+    ZipArchive,
+    result::{ZipError, ZipResult},
+};
+
+use crate::ldr::{Reference, reference_number};
+
+/// Where a [`Resolver`] looks for files that aren't part of the model itself.
+#[derive(Debug, Clone, Default)]
+pub struct ResolverConfig {
+    /// LDraw library roots (directories containing `parts` and `p`, or zips of them),
+    /// searched in order.
+    pub libraries: Vec<PathBuf>,
+    /// Custom part roots, laid out like a library and searched before any of them.
+    pub custom_parts: Vec<PathBuf>,
+    /// Whether to also search the `UnOfficial` tree of each library.
+    pub unofficial: bool,
+    /// Which primitive resolutions to prefer.
+    pub primitives: PrimitiveQuality,
+    /// Whether to stand in [`PLACEHOLDER`] for files that can't be found, rather than failing.
+    pub substitute_missing: bool,
+}
+
+/// The order in which a library's primitive directories are searched.
+#[derive(Debug, Clone, Default)]
+pub enum PrimitiveQuality {
+    /// Low-resolution `p/8` first, for huge scenes.
+    Low,
+    /// Regular `p` first.
+    #[default]
+    Normal,
+    /// High-resolution `p/48` first, for print.
+    High,
+    /// Subdirectories of `p` in the given order, with `""` meaning `p` itself.
+    Explicit(Vec<String>),
+}
+
+impl PrimitiveQuality {
+    fn library_dirs(&self) -> Vec<String> {
+        let order = match self {
+            Self::Low => vec!["8", "", "48", "4"],
+            Self::Normal => vec!["", "8", "48", "4"],
+            Self::High => vec!["48", "", "8", "4"],
+            Self::Explicit(order) => order.iter().map(String::as_str).collect(),
+        };
+        let primitives = order.into_iter().map(primitive_dir);
+        std::iter::once("parts".into()).chain(primitives).collect()
+    }
+}
+
+fn primitive_dir(subdir: &str) -> String {
+    if subdir.is_empty() {
+        "p".into()
+    } else {
+        format!("p/{subdir}")
+    }
+}
+
+impl ResolverConfig {
+    /// The layout of a default Stud.io installation.
+    pub fn studio() -> Self {
+        Self {
+            libraries: vec!["C:/Program Files/Studio 2.0/ldraw".into()],
+            custom_parts: dirs::data_local_dir()
+                .map(|dir| dir.join("Stud.io/CustomParts"))
+                .into_iter()
+                .collect(),
+            unofficial: true,
+            primitives: PrimitiveQuality::Normal,
+            substitute_missing: false,
+        }
+    }
+
+    /// Reads library roots from `LDRAWDIR` (a path list, like `PATH`),
+    /// falling back to [`Self::studio`] if it isn't set.
+    pub fn from_env() -> Self {
+        let Some(var) = std::env::var_os("LDRAWDIR") else {
+            return Self::studio();
+        };
+        Self {
+            libraries: std::env::split_paths(&var).collect(),
+            custom_parts: vec![],
+            unofficial: true,
+            primitives: PrimitiveQuality::Normal,
+            substitute_missing: false,
+        }
+    }
+}
+
+const CUSTOM_DIRS: [&str; 2] = ["parts", "p"];
+
+/// A library root, either extracted on disk or as a zip like the distributed `complete.zip`.
+///
+/// Lookups take paths as produced by [`normalize`].
+enum Library {
+    Dir(DirLibrary),
+    Zip(ZipLibrary),
+}
+
+struct DirLibrary {
+    root: PathBuf,
+    /// Contents of each directory visited so far, keyed by normalized name.
+    listings: Mutex<HashMap<PathBuf, HashMap<String, PathBuf>>>,
+}
+
+struct ZipLibrary {
+    path: PathBuf,
+    archive: Mutex<ZipArchive<File>>,
+    /// Entry indices by normalized path relative to the library root.
+    index: HashMap<String, usize>,
+    password: Option<&'static [u8]>,
+}
+
+/// LDraw filenames are case-insensitive and may use either path separator.
+pub(crate) fn normalize(path: &str) -> String {
+    path.replace('\\', "/").to_lowercase()
+}
+
+impl Library {
+    fn open(path: &Path) -> ZipResult<Self> {
+        if path.extension() != Some("zip".as_ref()) {
+            return Ok(Self::Dir(DirLibrary {
+                root: path.to_owned(),
+                listings: Default::default(),
+            }));
+        }
+
+        let archive = ZipArchive::new(File::open(path)?)?;
+
+        // complete.zip nests everything in an `ldraw` folder; ldrawunf.zip doesn't
+        let names = (0..archive.len())
+            .map(|i| normalize(archive.name_for_index(i).unwrap_or_default()))
+            .collect::<Vec<_>>();
+        let prefix = if names.iter().any(|name| name.starts_with("ldraw/")) {
+            "ldraw/"
+        } else {
+            ""
+        };
+
+        let index = names
+            .into_iter()
+            .enumerate()
+            .filter_map(|(i, name)| Some((name.strip_prefix(prefix)?.to_owned(), i)))
+            .collect();
+
+        Ok(Self::Zip(ZipLibrary {
+            path: path.to_owned(),
+            archive: Mutex::new(archive),
+            index,
+            password: None,
+        }))
+    }
+
+    fn read(&self, path: &str) -> Option<ZipResult<Vec<u8>>> {
+        match self {
+            Self::Dir(lib) => {
+                let path = lib.find(path)?;
+                Some(std::fs::read(path).map_err(Into::into))
+            }
+            Self::Zip(lib) => lib.read(path),
+        }
+    }
+
+    /// Reads only as much of a file as [`read_header`] needs.
+    fn read_header(&self, path: &str) -> Option<ZipResult<Vec<u8>>> {
+        match self {
+            Self::Dir(lib) => {
+                let path = lib.find(path)?;
+                let header = File::open(path).and_then(|mut f| read_header(&mut f));
+                Some(header.map_err(Into::into))
+            }
+            Self::Zip(lib) => {
+                let i = *lib.index.get(path)?;
+                Some(lib.read_index(i, read_header))
+            }
+        }
+    }
+
+    /// Finds the file on disk holding `path`, which for zip libraries is the zip itself.
+    fn locate(&self, path: &str) -> Option<PathBuf> {
+        match self {
+            Self::Dir(lib) => lib.find(path),
+            Self::Zip(lib) => lib.index.contains_key(path).then(|| lib.path.clone()),
+        }
+    }
+
+    /// Lists every file under `dir`, recursively, by normalized path relative to `dir`.
+    fn list(&self, dir: &str) -> Vec<String> {
+        match self {
+            Self::Dir(lib) => {
+                let mut output = vec![];
+                if let Some(path) = lib.walk(dir) {
+                    list_recursive(&path, "", &mut output);
+                }
+                output
+            }
+            Self::Zip(lib) => {
+                let prefix = format!("{dir}/");
+                lib.index
+                    .keys()
+                    .filter_map(|name| name.strip_prefix(&prefix))
+                    .map(str::to_owned)
+                    .collect()
+            }
+        }
+    }
+
+    fn root(&self) -> &Path {
+        match self {
+            Self::Dir(lib) => &lib.root,
+            Self::Zip(lib) => &lib.path,
+        }
+    }
+}
+
+impl ZipLibrary {
+    /// Indexes a Stud.io `.io` archive by every trailing portion of each entry's path,
+    /// so that `s\foo.dat` finds `CustomParts/parts/s/foo.dat` wherever Studio put it.
+    fn open_io(path: &Path) -> ZipResult<Self> {
+        let archive = ZipArchive::new(File::open(path)?)?;
+        let names = (0..archive.len())
+            .map(|i| normalize(archive.name_for_index(i).unwrap_or_default()))
+            .collect::<Vec<_>>();
+
+        // full paths win over suffixes of other entries
+        let mut index = HashMap::new();
+        for (i, name) in names.iter().enumerate() {
+            index.entry(name.clone()).or_insert(i);
+        }
+        for (i, name) in names.iter().enumerate() {
+            for (sep, _) in name.match_indices('/') {
+                index.entry(name[sep + 1..].to_owned()).or_insert(i);
+            }
+        }
+
+        Ok(Self {
+            path: path.to_owned(),
+            archive: Mutex::new(archive),
+            index,
+            password: Some(crate::IO_PASSWORD),
+        })
+    }
+
+    fn read(&self, path: &str) -> Option<ZipResult<Vec<u8>>> {
+        let i = *self.index.get(path)?;
+        Some(self.read_index(i, |file| {
+            let mut buf = Vec::new();
+            file.read_to_end(&mut buf)?;
+            Ok(buf)
+        }))
+    }
+
+    fn read_index<T>(
+        &self,
+        index: usize,
+        read: impl FnOnce(&mut dyn Read) -> std::io::Result<T>,
+    ) -> ZipResult<T> {
+        let mut archive = self.archive.lock().unwrap();
+        let encrypted = archive.by_index_raw(index)?.encrypted();
+        let mut file = match self.password {
+            Some(password) if encrypted => archive.by_index_decrypt(index, password)?,
+            _ => archive.by_index(index)?,
+        };
+        Ok(read(&mut file)?)
+    }
+}
+
+impl DirLibrary {
+    fn find(&self, path: &str) -> Option<PathBuf> {
+        self.walk(path).filter(|path| path.is_file())
+    }
+
+    /// Finds a path by walking the cached listing of each directory along the way,
+    /// rather than trying every spelling of it.
+    fn walk(&self, path: &str) -> Option<PathBuf> {
+        let mut listings = self.listings.lock().unwrap();
+        let mut current = self.root.clone();
+        for component in path.split('/') {
+            let listing = listings
+                .entry(current.clone())
+                .or_insert_with(|| list_dir(&current));
+            current = listing.get(component)?.clone();
+        }
+        Some(current)
+    }
+}
+
+fn list_recursive(dir: &Path, prefix: &str, output: &mut Vec<String>) {
+    for (name, path) in list_dir(dir) {
+        let name = format!("{prefix}{name}");
+        if path.is_dir() {
+            list_recursive(&path, &format!("{name}/"), output);
+        } else {
+            output.push(name);
+        }
+    }
+}
+
+fn list_dir(dir: &Path) -> HashMap<String, PathBuf> {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return HashMap::new();
+    };
+    entries
+        .filter_map(Result::ok)
+        .map(|entry| {
+            (
+                entry.file_name().to_string_lossy().to_lowercase(),
+                entry.path(),
+            )
+        })
+        .collect()
+}
+
+/// Reads the start of an LDraw file, up to and including its first line that isn't a comment
+/// or meta-command, which is all a header can take up.
+fn read_header(reader: &mut dyn Read) -> std::io::Result<Vec<u8>> {
+    let mut reader = BufReader::new(reader);
+    let mut buf = Vec::new();
+    loop {
+        let start = buf.len();
+        if reader.read_until(b'\n', &mut buf)? == 0 {
+            break;
+        }
+        let line = &buf[start..];
+        let line = line.strip_prefix("\u{FEFF}".as_bytes()).unwrap_or(line);
+        let line = line.trim_ascii();
+        if !line.is_empty() && !line.starts_with(b"0") {
+            break;
+        }
+    }
+    Ok(strip_bom(buf))
+}
+
+/// Studio writes its files with a byte order mark, which weldr doesn't expect.
+fn strip_bom(mut buf: Vec<u8>) -> Vec<u8> {
+    if buf.starts_with("\u{FEFF}".as_bytes()) {
+        buf.drain(..3);
+    }
+    buf
+}
 
 pub struct Resolver {
     root: Vec<u8>,
     root_filename: PathBuf,
+    /// The Stud.io archive the root came from, if any.
+    archive: Option<ZipLibrary>,
+    libraries: Vec<Library>,
+    /// Library indices and the directories within them, in search order.
+    search_dirs: Vec<(usize, String)>,
+    /// How many of the libraries are custom part roots, which come first.
+    custom_libraries: usize,
+    /// Files substituted with [`PLACEHOLDER`], if `substitute_missing` is set.
+    missing: Option<Mutex<Vec<MissingFile>>>,
 }
 
 impl Resolver {
-    pub fn new(path: impl AsRef<Path>) -> zip::result::ZipResult<Self> {
+    pub fn new(path: impl AsRef<Path>, config: ResolverConfig) -> ZipResult<Self> {
         let path = path.as_ref();
-        let contents = if path.extension() == Some("io".as_ref()) {
-            let f = File::open(path)?;
-            let mut zip = ZipArchive::new(f)?;
-            let mut ldr_file = zip.by_name("model.ldr")?;
-            let mut buf = Vec::with_capacity(ldr_file.size() as usize);
-
-            // skip the byte order mark, if present
-            ldr_file.by_ref().take(3).read_to_end(&mut buf)?;
-            if buf == "\u{FEFF}".as_bytes() {
-                buf.clear();
-            }
-
-            ldr_file.read_to_end(&mut buf)?;
-            buf
+        let (contents, archive) = if path.extension() == Some("io".as_ref()) {
+            let archive = ZipLibrary::open_io(path)?;
+            let contents = archive
+                .read("model.ldr")
+                .unwrap_or(Err(ZipError::FileNotFound))?;
+            (strip_bom(contents), Some(archive))
         } else {
-            std::fs::read(path)?
+            (std::fs::read(path)?, None)
         };
 
+        let mut libraries = vec![];
+        let mut search_dirs = vec![];
+
+        for root in &config.custom_parts {
+            search_dirs.extend(CUSTOM_DIRS.map(|dir| (libraries.len(), dir.to_owned())));
+            libraries.push(Library::open(root)?);
+        }
+
+        let library_dirs = config.primitives.library_dirs();
+        let official = libraries.len()..libraries.len() + config.libraries.len();
+        for root in &config.libraries {
+            search_dirs.extend(
+                library_dirs
+                    .iter()
+                    .map(|dir| (libraries.len(), dir.clone())),
+            );
+            libraries.push(Library::open(root)?);
+        }
+
+        if config.unofficial {
+            for i in official {
+                search_dirs.extend(
+                    library_dirs
+                        .iter()
+                        .map(|dir| (i, format!("UnOfficial/{dir}"))),
+                );
+            }
+        }
+
         Ok(Self {
             root: contents,
             root_filename: path.file_name().unwrap().into(),
+            archive,
+            libraries,
+            search_dirs,
+            custom_libraries: config.custom_parts.len(),
+            missing: config.substitute_missing.then(Default::default),
         })
     }
+
+    /// Lists the `0 FILE` sections of a multi-part document, in order.
+    ///
+    /// This is empty for a plain single-model file.
+    pub fn submodels(&self) -> Vec<String> {
+        String::from_utf8_lossy(&self.root)
+            .lines()
+            .filter_map(|line| {
+                let rest = line.trim_start().strip_prefix('0')?.trim_start();
+                let (meta, name) = rest.split_once(char::is_whitespace)?;
+                meta.eq_ignore_ascii_case("FILE")
+                    .then(|| name.trim().to_owned())
+            })
+            .collect()
+    }
+
+    /// Picks the model to render: the submodel named by `choice` if there is one,
+    /// or else `main`, as returned by [`weldr::parse`].
+    pub fn choose_model(&self, main: String, choice: Option<&str>) -> Result<String, UnknownModel> {
+        let Some(choice) = choice else {
+            return Ok(main);
+        };
+        let submodels = self.submodels();
+        submodels
+            .iter()
+            .find(|name| name.eq_ignore_ascii_case(choice))
+            .cloned()
+            .ok_or_else(|| UnknownModel {
+                name: choice.to_owned(),
+                submodels,
+            })
+    }
+
+    /// Reads the header of a file, as [`resolve`](FileRefResolver::resolve) would find it.
+    pub(crate) fn header(&self, filename: &str) -> Option<ZipResult<Vec<u8>>> {
+        let filename = normalize(filename);
+        if let Some(archive) = &self.archive
+            && let Some(&i) = archive.index.get(&filename)
+        {
+            return Some(archive.read_index(i, read_header));
+        }
+        self.search_dirs.iter().find_map(|(library, dir)| {
+            self.libraries[*library].read_header(&normalize(&format!("{dir}/{filename}")))
+        })
+    }
+
+    /// Reads a file from the root of the first library that has one, such as `LDConfig.ldr`.
+    pub fn library_file(&self, name: &str) -> Option<ZipResult<Vec<u8>>> {
+        let name = normalize(name);
+        self.libraries
+            .iter()
+            .find_map(|library| library.read(&name))
+    }
+
+    /// Finds Stud.io's own color definitions, next to a library it installed.
+    pub fn studio_color_definitions(&self) -> Option<PathBuf> {
+        self.libraries
+            .iter()
+            .filter_map(|library| library.root().parent())
+            .map(|dir| dir.join("data/StudioColorDefinition.txt"))
+            .find(|path| path.is_file())
+    }
+
+    /// Lists every file in the libraries' `parts` directories, including subparts,
+    /// by normalized name. Shadowed files are only listed once.
+    pub fn part_names(&self) -> Vec<String> {
+        let mut seen = HashSet::new();
+        let mut output = vec![];
+        for (library, dir) in &self.search_dirs {
+            if !dir.ends_with("parts") {
+                continue;
+            }
+            for name in self.libraries[*library].list(&normalize(dir)) {
+                if seen.insert(name.clone()) {
+                    output.push(name);
+                }
+            }
+        }
+        output
+    }
+
+    /// Finds where in the libraries a file would be resolved from.
+    ///
+    /// Returns `None` for anything that isn't a library file, including the model itself.
+    pub(crate) fn locate(&self, filename: &str) -> Option<Location> {
+        if self.root_filename == Path::new(filename) {
+            return None;
+        }
+
+        let filename = normalize(filename);
+        if self
+            .archive
+            .as_ref()
+            .is_some_and(|archive| archive.index.contains_key(&filename))
+        {
+            return None;
+        }
+
+        self.search_dirs.iter().find_map(|(library, dir)| {
+            let path = self.libraries[*library].locate(&normalize(&format!("{dir}/{filename}")))?;
+            let modified = path.metadata().and_then(|m| m.modified()).ok()?;
+            Some(Location {
+                path,
+                modified,
+                is_part: dir.ends_with("parts") && !filename.starts_with("s/"),
+                is_custom: *library < self.custom_libraries,
+            })
+        })
+    }
+
+    /// Whether a file comes from the Stud.io archive or a custom part root,
+    /// rather than from a library or the model itself.
+    pub fn is_custom(&self, filename: &str) -> bool {
+        if self.root_filename == Path::new(filename) {
+            return false;
+        }
+
+        let filename = normalize(filename);
+        if filename == "model.ldr" {
+            return false;
+        }
+        if self
+            .archive
+            .as_ref()
+            .is_some_and(|archive| archive.index.contains_key(&filename))
+        {
+            return true;
+        }
+
+        self.search_dirs
+            .iter()
+            .find(|(library, dir)| {
+                self.libraries[*library]
+                    .locate(&normalize(&format!("{dir}/{filename}")))
+                    .is_some()
+            })
+            .is_some_and(|(library, _)| *library < self.custom_libraries)
+    }
+
+    /// Hashes the libraries and the order they're searched in,
+    /// which together decide what every filename resolves to.
+    pub(crate) fn hash_search_order<H: Hasher>(&self, state: &mut H) {
+        for (library, dir) in &self.search_dirs {
+            self.libraries[*library].root().hash(state);
+            dir.hash(state);
+        }
+    }
+
+    /// Records that a missing file was needed by a library part, which a [`PartCache`] flattens
+    /// so the reference to it never makes it into the model's source map.
+    ///
+    /// [`PartCache`]: crate::cache::PartCache
+    pub(crate) fn missing_beneath(&self, filename: &str, part: &str) {
+        let Some(missing) = &self.missing else {
+            return;
+        };
+        let mut missing = missing.lock().unwrap();
+        if let Some(entry) = missing.iter_mut().find(|m| m.filename == filename)
+            && !entry.parts.iter().any(|p| p == part)
+        {
+            entry.parts.push(part.to_owned());
+        }
+    }
+
+    /// Lists every file that was substituted with [`PLACEHOLDER`], along with the references
+    /// reachable from `model_name` to it or to the library parts it was needed by.
+    pub fn missing_report(&self, source_map: &SourceMap, model_name: &str) -> Vec<MissingFile> {
+        let Some(missing) = &self.missing else {
+            return vec![];
+        };
+
+        let mut report = missing.lock().unwrap().clone();
+
+        let mut visited = HashSet::new();
+        let mut queue = vec![model_name.to_owned()];
+        while let Some(name) = queue.pop() {
+            let Some(file) = source_map.get(&name) else {
+                continue;
+            };
+            for (i, cmd) in file.cmds.iter().enumerate() {
+                let Command::SubFileRef(sfrc) = cmd else {
+                    continue;
+                };
+                let mut found = false;
+                for entry in &mut report {
+                    if entry.filename == sfrc.file || entry.parts.contains(&sfrc.file) {
+                        entry.references.push(Reference {
+                            model: name.clone(),
+                            reference: reference_number(file, i),
+                            file: sfrc.file.clone(),
+                        });
+                        found = true;
+                    }
+                }
+                if !found && visited.insert(sfrc.file.clone()) {
+                    queue.push(sfrc.file.clone());
+                }
+            }
+        }
+
+        report
+    }
+}
+
+/// A submodel was requested that the document doesn't contain.
+#[derive(Debug, Clone)]
+pub struct UnknownModel {
+    pub name: String,
+    pub submodels: Vec<String>,
 }
 
+impl std::fmt::Display for UnknownModel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "no submodel named {}", self.name)?;
+        if self.submodels.is_empty() {
+            write!(f, " (the document has no submodels)")
+        } else {
+            write!(f, " (expected one of: {})", self.submodels.join(", "))
+        }
+    }
+}
+
+impl std::error::Error for UnknownModel {}
+
+pub(crate) struct Location {
+    /// The file on disk holding it, which for zip libraries is the zip itself.
+    pub path: PathBuf,
+    pub modified: SystemTime,
+    /// Whether it's a part proper, rather than a subpart or primitive.
+    pub is_part: bool,
+    /// Whether it comes from a custom part root.
+    pub is_custom: bool,
+}
+
+/// A file that couldn't be resolved, and where it was referenced.
+#[derive(Debug, Clone)]
+pub struct MissingFile {
+    pub filename: String,
+    /// The library parts it was needed by, if it was missing from beneath any.
+    pub parts: Vec<String>,
+    /// The references to it, or to the parts it was needed by.
+    pub references: Vec<Reference>,
+}
+
+impl std::fmt::Display for MissingFile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "missing {}", self.filename)?;
+        for part in &self.parts {
+            write!(f, "\n    needed by {part}")?;
+        }
+        for reference in &self.references {
+            let Reference {
+                model,
+                reference,
+                file,
+            } = reference;
+            write!(
+                f,
+                "\n    {file} referenced by {model} (subfile reference #{reference})"
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// A magenta cube, in place of files that couldn't be found.
+pub const PLACEHOLDER: &str = "\
+0 Missing file placeholder
+0 BFC CERTIFY CCW
+4 26 -10 -10 10  -10 10 10  -10 10 -10  -10 -10 -10
+4 26 10 -10 -10  10 10 -10  10 10 10  10 -10 10
+4 26 -10 -10 -10  10 -10 -10  10 -10 10  -10 -10 10
+4 26 -10 10 10  10 10 10  10 10 -10  -10 10 -10
+4 26 -10 10 -10  10 10 -10  10 -10 -10  -10 -10 -10
+4 26 -10 -10 10  10 -10 10  10 10 10  -10 10 10
+2 24 -10 -10 -10  -10 -10 10
+2 24 -10 -10 -10  -10 10 -10
+2 24 -10 -10 -10  10 -10 -10
+2 24 -10 -10 10  -10 10 10
+2 24 -10 -10 10  10 -10 10
+2 24 -10 10 -10  -10 10 10
+2 24 -10 10 -10  10 10 -10
+2 24 -10 10 10  10 10 10
+2 24 10 -10 -10  10 -10 10
+2 24 10 -10 -10  10 10 -10
+2 24 10 -10 10  10 10 10
+2 24 10 10 -10  10 10 10
+";
+
 impl FileRefResolver for Resolver {
     fn resolve<P: AsRef<Path>>(&self, filename: P) -> Result<Vec<u8>, weldr::ResolveError> {
         let filename = filename.as_ref();
@@ -47,30 +712,37 @@ impl FileRefResolver for Resolver {
             return Ok(self.root.clone());
         }
 
-        let ldraw = Path::new("C:/Program Files/Studio 2.0/ldraw");
-        let custom = dirs::data_local_dir().unwrap().join("Stud.io/CustomParts");
-
-        for dir in [
-            custom.join("parts"),
-            ldraw.join("parts"),
-            // primitive quality order: normal, low, high, very low
-            ldraw.join("p"),
-            ldraw.join("p/8"),
-            ldraw.join("p/48"),
-            ldraw.join("p/4"),
-            ldraw.join("UnOfficial/parts"),
-            ldraw.join("UnOfficial/p"),
-            ldraw.join("UnOfficial/p/8"),
-            ldraw.join("UnOfficial/p/48"),
-            ldraw.join("UnOfficial/p/4"),
-        ] {
-            let path = dir.join(&filename);
-            if path.exists() {
-                return std::fs::read(path).map_err(|e| {
-                    weldr::ResolveError::new(filename.to_string_lossy().into_owned(), e)
+        let to_resolve_error =
+            |e| weldr::ResolveError::new(filename.to_string_lossy().into_owned(), e);
+
+        if let Some(archive) = &self.archive {
+            let path = normalize(&filename.to_string_lossy());
+            if let Some(contents) = archive.read(&path) {
+                return contents.map(strip_bom).map_err(to_resolve_error);
+            }
+        }
+
+        for (library, dir) in &self.search_dirs {
+            let path = normalize(&format!("{dir}/{}", filename.display()));
+            if let Some(contents) = self.libraries[*library].read(&path) {
+                return contents.map_err(to_resolve_error);
+            }
+        }
+
+        if let Some(missing) = &self.missing {
+            let filename = filename.to_string_lossy();
+            let mut missing = missing.lock().unwrap();
+            // a part cache asks again for any it leaves referenced
+            if !missing.iter().any(|m| m.filename == filename) {
+                missing.push(MissingFile {
+                    filename: filename.into_owned(),
+                    parts: vec![],
+                    references: vec![],
                 });
             }
+            return Ok(PLACEHOLDER.into());
         }
+
         Err(weldr::ResolveError::new_raw(
             filename.to_string_lossy().as_ref(),
         ))
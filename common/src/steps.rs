@@ -0,0 +1,236 @@
+use std::ops::Range;
+
+use weldr::{Command, Mat4, SourceMap, SubFileRefCmd, Vec3};
+
+use crate::ldr::{Bfc, ColorCode, GeometryContext, TraverseError, get_model};
+
+/// A subfile reference in a model, in the model's own space.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Placement {
+    pub file: String,
+    pub color: ColorCode,
+    pub transform: Mat4,
+    /// Whether it was inverted with `BFC INVERTNEXT`, on top of any mirroring in `transform`.
+    pub inverted: bool,
+    /// Whether BFC clipping was on where it was referenced. See [`Bfc::clipping`].
+    pub clip: bool,
+}
+
+impl Placement {
+    /// The reference at this point in a model, taking up any `INVERTNEXT` before it.
+    pub(crate) fn new(bfc: &mut Bfc, sfrc: &SubFileRefCmd) -> Self {
+        let ctx = GeometryContext {
+            transform: Mat4::IDENTITY,
+            ..GeometryContext::new()
+        };
+        let child = bfc.child(&ctx, sfrc);
+        Self {
+            file: sfrc.file.clone(),
+            color: sfrc.color,
+            transform: sfrc.matrix(),
+            inverted: child.inverted,
+            clip: child.clip,
+        }
+    }
+}
+
+/// The building steps of a model, and the references they add.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Steps {
+    /// Every reference in the model, in order.
+    pub placements: Vec<Placement>,
+    pub steps: Vec<Step>,
+}
+
+/// One building step of a model.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Step {
+    /// The references added in this step, as a range of [`Steps::placements`].
+    /// Submodels are listed as a single reference; see [`steps`] for their own steps.
+    pub added: Range<usize>,
+    /// The view rotation in effect for this step, if one was set with `ROTSTEP`.
+    pub rotation: Option<Rotation>,
+}
+
+impl Steps {
+    /// References added in a step.
+    pub fn added(&self, step: &Step) -> &[Placement] {
+        &self.placements[step.added.clone()]
+    }
+
+    /// Every reference added up to and including a step.
+    pub fn cumulative(&self, step: &Step) -> &[Placement] {
+        &self.placements[..step.added.end]
+    }
+}
+
+/// A view rotation from `0 ROTSTEP x y z [REL|ABS|ADD]`, in degrees.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rotation {
+    pub angles: Vec3,
+    pub mode: RotationMode,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RotationMode {
+    /// Relative to the default view. This is the default.
+    Relative,
+    /// Replacing the default view.
+    Absolute,
+    /// Added to the previous step's rotation.
+    Additive,
+}
+
+/// Splits a model into its steps, in order.
+///
+/// Steps end at `0 STEP` or `0 ROTSTEP`, and any references after the last one
+/// form a final step of their own.
+pub fn steps(source_map: &SourceMap, model_name: &str) -> Result<Steps, TraverseError> {
+    let model = get_model(source_map, model_name)?;
+
+    let mut output = Steps::default();
+    let mut start = 0;
+    let mut rotation = None;
+    let mut bfc = Bfc::new(model, &GeometryContext::new());
+
+    for cmd in &model.cmds {
+        bfc.apply(cmd);
+        match cmd {
+            Command::SubFileRef(sfrc) => {
+                output.placements.push(Placement::new(&mut bfc, sfrc));
+            }
+            Command::Comment(c) => {
+                let mut words = c.text.split_whitespace();
+                match words.next() {
+                    Some("STEP") => {}
+                    Some("ROTSTEP") => rotation = parse_rotstep(words, rotation),
+                    _ => continue,
+                }
+                let end = output.placements.len();
+                output.steps.push(Step {
+                    added: start..end,
+                    rotation,
+                });
+                start = end;
+            }
+            _ => {}
+        }
+    }
+
+    let end = output.placements.len();
+    if start < end {
+        output.steps.push(Step {
+            added: start..end,
+            rotation,
+        });
+    }
+
+    Ok(output)
+}
+
+/// Applies the arguments of a `ROTSTEP` to the rotation in effect.
+fn parse_rotstep<'a>(
+    mut words: impl Iterator<Item = &'a str>,
+    previous: Option<Rotation>,
+) -> Option<Rotation> {
+    let first = words.next()?;
+    if first == "END" {
+        return None;
+    }
+
+    let mut angles = [0.0; 3];
+    for (i, word) in std::iter::once(first)
+        .chain(words.by_ref())
+        .take(3)
+        .enumerate()
+    {
+        angles[i] = word.parse().unwrap_or(0.0);
+    }
+    let angles = Vec3::from(angles);
+
+    match words.next() {
+        Some("ABS") => Some(Rotation {
+            angles,
+            mode: RotationMode::Absolute,
+        }),
+        Some("ADD") => Some(match previous {
+            Some(previous) => Rotation {
+                angles: previous.angles + angles,
+                ..previous
+            },
+            None => Rotation {
+                angles,
+                mode: RotationMode::Relative,
+            },
+        }),
+        _ => Some(Rotation {
+            angles,
+            mode: RotationMode::Relative,
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rotstep(args: &str, previous: Option<Rotation>) -> Option<Rotation> {
+        parse_rotstep(args.split_whitespace(), previous)
+    }
+
+    fn rotation(x: f32, y: f32, z: f32, mode: RotationMode) -> Option<Rotation> {
+        Some(Rotation {
+            angles: Vec3::new(x, y, z),
+            mode,
+        })
+    }
+
+    #[test]
+    fn modes() {
+        use RotationMode::*;
+        assert_eq!(
+            rotstep("10 20 30", None),
+            rotation(10.0, 20.0, 30.0, Relative)
+        );
+        assert_eq!(
+            rotstep("10 20 30 REL", None),
+            rotation(10.0, 20.0, 30.0, Relative)
+        );
+        assert_eq!(
+            rotstep("10 20 30 ABS", None),
+            rotation(10.0, 20.0, 30.0, Absolute)
+        );
+    }
+
+    #[test]
+    fn end_clears() {
+        let previous = rotation(10.0, 0.0, 0.0, RotationMode::Absolute);
+        assert_eq!(rotstep("END", previous), None);
+        assert_eq!(rotstep("", previous), None);
+    }
+
+    #[test]
+    fn add_stacks_onto_previous() {
+        let first = rotstep("10 20 30 ABS", None);
+        let second = rotstep("5 0 -30 ADD", first);
+        assert_eq!(second, rotation(15.0, 20.0, 0.0, RotationMode::Absolute));
+        let third = rotstep("5 0 0 ADD", second);
+        assert_eq!(third, rotation(20.0, 20.0, 0.0, RotationMode::Absolute));
+    }
+
+    #[test]
+    fn add_without_previous_is_relative() {
+        assert_eq!(
+            rotstep("5 0 0 ADD", None),
+            rotation(5.0, 0.0, 0.0, RotationMode::Relative)
+        );
+    }
+
+    #[test]
+    fn bad_angles_are_zero() {
+        assert_eq!(
+            rotstep("x 20 30", None),
+            rotation(0.0, 20.0, 30.0, RotationMode::Relative)
+        );
+    }
+}
@@ -0,0 +1,350 @@
+use std::{
+    collections::HashSet,
+    fmt::Write,
+    path::{Path, PathBuf},
+};
+
+use weldr::{Color, ColorFinish, ColourCmd, Command, GrainSize, Mat4, MaterialFinish, SourceMap};
+
+use crate::{
+    Result,
+    hierarchy::is_part,
+    ldr::{Ancestors, Bfc, ColorCode, GeometryContext, TraverseError, get_model, reference_number},
+    resolver::{Resolver, normalize},
+};
+
+/// How a [`Writer`] lays out a model.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct WriteOptions {
+    /// Merges every submodel into the model referencing it, leaving a single model of parts.
+    pub flatten: bool,
+    /// Writes parts from the Stud.io archive or a custom part root out with the model,
+    /// so other tools don't need them installed.
+    pub inline_custom_parts: bool,
+}
+
+/// Writes a model and its submodels from a [`SourceMap`] back out as LDraw.
+pub struct Writer<'a> {
+    source_map: &'a SourceMap,
+    resolver: &'a Resolver,
+    pub options: WriteOptions,
+    /// The `0 FILE` sections of the document, by normalized name.
+    sections: HashSet<String>,
+}
+
+impl<'a> Writer<'a> {
+    pub fn new(source_map: &'a SourceMap, resolver: &'a Resolver, options: WriteOptions) -> Self {
+        Self {
+            source_map,
+            resolver,
+            options,
+            sections: resolver
+                .submodels()
+                .iter()
+                .map(|name| normalize(name))
+                .collect(),
+        }
+    }
+
+    /// Writes everything as one multi-part document, with the model first.
+    pub fn to_mpd(&self, model_name: &str) -> Result<String, TraverseError> {
+        let mut output = String::new();
+        for name in self.files(model_name)? {
+            writeln!(output, "0 FILE {}", output_name(&name)).unwrap();
+            output.push_str(&self.contents(&name, name == model_name)?);
+        }
+        Ok(output)
+    }
+
+    /// Writes each file to its own `.ldr` (or `.dat`, for parts) under `dir`,
+    /// returning their paths with the model's first.
+    ///
+    /// Files keep the names they're referenced by, so subpart directories like `s` are created
+    /// as needed and other tools find them relative to the model.
+    pub fn to_dir(&self, model_name: &str, dir: impl AsRef<Path>) -> Result<Vec<PathBuf>> {
+        let mut paths = vec![];
+        for name in self.files(model_name)? {
+            let contents = self.contents(&name, name == model_name)?;
+            let path = output_name(&name)
+                .split('/')
+                .filter(|component| !matches!(*component, "" | "." | ".."))
+                .fold(dir.as_ref().to_owned(), |path, component| {
+                    path.join(component)
+                });
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            std::fs::write(&path, contents)?;
+            paths.push(path);
+        }
+        Ok(paths)
+    }
+
+    /// The files to write: the model, then every other file the document defines
+    /// and any inlined parts, each after the first file referencing it.
+    fn files(&self, model_name: &str) -> Result<Vec<String>, TraverseError> {
+        let mut seen = HashSet::from([model_name.to_owned()]);
+        let mut output = vec![];
+        let mut ancestors = Ancestors::default();
+        self.collect_files(model_name, true, &mut seen, &mut output, &mut ancestors)?;
+        Ok(output)
+    }
+
+    fn collect_files<'b>(
+        &'b self,
+        name: &'b str,
+        listed: bool,
+        seen: &mut HashSet<String>,
+        output: &mut Vec<String>,
+        ancestors: &mut Ancestors<'b>,
+    ) -> Result<(), TraverseError> {
+        let file = get_model(self.source_map, name)?;
+        ancestors.enter(name)?;
+        if listed {
+            output.push(name.to_owned());
+        }
+
+        for (i, cmd) in file.cmds.iter().enumerate() {
+            let Command::SubFileRef(sfrc) = cmd else {
+                continue;
+            };
+            let part = is_part(self.source_map, &sfrc.file);
+            let written = self.sections.contains(&normalize(&sfrc.file))
+                || (self.options.inline_custom_parts && self.resolver.is_custom(&sfrc.file));
+            if part && !written {
+                continue;
+            }
+            // a flattened submodel is merged into its parent, but still needs its own files
+            let listed = part || !self.options.flatten;
+            if listed && !seen.insert(sfrc.file.clone()) {
+                continue;
+            }
+            self.collect_files(&sfrc.file, listed, seen, output, ancestors)
+                .map_err(|e| e.referenced_by(name, reference_number(file, i)))?;
+        }
+
+        ancestors.leave();
+        Ok(())
+    }
+
+    fn contents(&self, name: &str, is_root: bool) -> Result<String, TraverseError> {
+        let mut output = String::new();
+        if is_root && self.options.flatten {
+            let ctx = GeometryContext {
+                transform: Mat4::IDENTITY,
+                projection: Mat4::IDENTITY,
+                ..GeometryContext::new()
+            };
+            self.write_flattened(&mut output, name, ctx, true, &mut Ancestors::default())?;
+        } else {
+            let file = get_model(self.source_map, name)?;
+            for cmd in &file.cmds {
+                if !is_file_marker(cmd) {
+                    write_command(&mut output, cmd);
+                }
+            }
+        }
+        Ok(output)
+    }
+
+    /// Writes a model with its submodels merged in, in place of the references to them.
+    ///
+    /// Only the top-level model keeps its comments and BFC statements, and `INVERTNEXT` is moved
+    /// onto the parts it ends up applying to.
+    fn write_flattened<'b>(
+        &'b self,
+        output: &mut String,
+        name: &'b str,
+        ctx: GeometryContext,
+        top: bool,
+        ancestors: &mut Ancestors<'b>,
+    ) -> Result<(), TraverseError> {
+        let file = get_model(self.source_map, name)?;
+        ancestors.enter(name)?;
+        let mut bfc = Bfc::new(file, &ctx);
+
+        for (i, cmd) in file.cmds.iter().enumerate() {
+            bfc.apply(cmd);
+            match cmd {
+                Command::SubFileRef(sfrc) if !is_part(self.source_map, &sfrc.file) => {
+                    let child = bfc.child(&ctx, sfrc);
+                    self.write_flattened(output, &sfrc.file, child, false, ancestors)
+                        .map_err(|e| e.referenced_by(name, reference_number(file, i)))?;
+                }
+                Command::SubFileRef(sfrc) => {
+                    let child = bfc.child(&ctx, sfrc);
+                    if child.inverted {
+                        output.push_str("0 BFC INVERTNEXT\n");
+                    }
+                    write_reference(output, child.color, child.transform, &sfrc.file);
+                }
+                // only the top-level model can have any, as anything else with geometry is a part
+                Command::Line(_)
+                | Command::OptLine(_)
+                | Command::Triangle(_)
+                | Command::Quad(_) => {
+                    write_command(output, cmd);
+                }
+                Command::Colour(c) => write_colour(output, c),
+                // written along with whatever reference it applies to
+                Command::Comment(c) if is_invert_next(&c.text) => {}
+                Command::Comment(_) | Command::Category(_) | Command::Keywords(_) => {
+                    if top && !is_file_marker(cmd) {
+                        write_command(output, cmd);
+                    }
+                }
+            }
+        }
+
+        ancestors.leave();
+        Ok(())
+    }
+}
+
+/// Whether a command is an MPD section marker, which the writer supplies itself.
+fn is_file_marker(cmd: &Command) -> bool {
+    let Command::Comment(c) = cmd else {
+        return false;
+    };
+    let mut words = c.text.split_whitespace();
+    matches!(words.next(), Some("FILE" | "NOFILE"))
+}
+
+fn is_invert_next(comment: &str) -> bool {
+    comment.split_whitespace().eq(["BFC", "INVERTNEXT"])
+}
+
+/// Stud.io's root model is named after its archive, which other tools won't read as LDraw.
+fn output_name(name: &str) -> String {
+    let name = name.replace('\\', "/");
+    match name.strip_suffix(".io") {
+        Some(stem) => format!("{stem}.ldr"),
+        None => name,
+    }
+}
+
+/// Writes a single command as a line of LDraw.
+pub fn write_command(output: &mut String, cmd: &Command) {
+    match cmd {
+        Command::Comment(c) => writeln!(output, "0 {}", c.text).unwrap(),
+        Command::Category(c) => writeln!(output, "0 !CATEGORY {}", c.category).unwrap(),
+        Command::Keywords(k) => writeln!(output, "0 !KEYWORDS {}", k.keywords.join(", ")).unwrap(),
+        Command::Colour(c) => write_colour(output, c),
+        Command::SubFileRef(sfrc) => write_reference(output, sfrc.color, sfrc.matrix(), &sfrc.file),
+        Command::Line(l) => write_geometry(output, 2, l.color, &l.vertices),
+        Command::Triangle(t) => write_geometry(output, 3, t.color, &t.vertices),
+        Command::Quad(q) => write_geometry(output, 4, q.color, &q.vertices),
+        Command::OptLine(l) => {
+            let [a, b] = l.vertices;
+            let [c, d] = l.control_points;
+            write_geometry(output, 5, l.color, &[a, b, c, d]);
+        }
+    }
+}
+
+pub(crate) fn write_reference(output: &mut String, color: ColorCode, transform: Mat4, file: &str) {
+    let [x, y, z] = transform.w_axis.truncate().to_array();
+    let [a, d, g] = transform.x_axis.truncate().to_array();
+    let [b, e, h] = transform.y_axis.truncate().to_array();
+    let [c, f, i] = transform.z_axis.truncate().to_array();
+    write!(output, "1 {}", color_code(color)).unwrap();
+    for n in [x, y, z, a, b, c, d, e, f, g, h, i] {
+        write!(output, " {}", number(n)).unwrap();
+    }
+    writeln!(output, " {file}").unwrap();
+}
+
+fn write_geometry(output: &mut String, line_type: u8, color: ColorCode, vertices: &[weldr::Vec3]) {
+    write!(output, "{line_type} {}", color_code(color)).unwrap();
+    for v in vertices {
+        write!(output, " {} {} {}", number(v.x), number(v.y), number(v.z)).unwrap();
+    }
+    output.push('\n');
+}
+
+fn write_colour(output: &mut String, colour: &ColourCmd) {
+    write!(
+        output,
+        "0 !COLOUR {} CODE {} VALUE {} EDGE {}",
+        colour.name,
+        colour.code,
+        hex(colour.value),
+        hex(colour.edge),
+    )
+    .unwrap();
+    write_alpha_luminance(output, colour.alpha, colour.luminance);
+
+    match &colour.finish {
+        None => {}
+        Some(ColorFinish::Chrome) => output.push_str(" CHROME"),
+        Some(ColorFinish::Pearlescent) => output.push_str(" PEARLESCENT"),
+        Some(ColorFinish::Rubber) => output.push_str(" RUBBER"),
+        Some(ColorFinish::MatteMetallic) => output.push_str(" MATTE_METALLIC"),
+        Some(ColorFinish::Metal) => output.push_str(" METAL"),
+        Some(ColorFinish::Material(MaterialFinish::Glitter(g))) => {
+            write!(output, " MATERIAL GLITTER VALUE {}", hex(g.value)).unwrap();
+            write_alpha_luminance(output, g.alpha, g.luminance);
+            write!(
+                output,
+                " FRACTION {} VFRACTION {}",
+                number(g.fraction),
+                number(g.vfraction),
+            )
+            .unwrap();
+            write_grain_size(output, &g.size);
+        }
+        Some(ColorFinish::Material(MaterialFinish::Speckle(s))) => {
+            write!(output, " MATERIAL SPECKLE VALUE {}", hex(s.value)).unwrap();
+            write_alpha_luminance(output, s.alpha, s.luminance);
+            write!(output, " FRACTION {}", number(s.fraction)).unwrap();
+            write_grain_size(output, &s.size);
+        }
+        Some(ColorFinish::Material(MaterialFinish::Other(other))) => {
+            write!(output, " MATERIAL {other}").unwrap();
+        }
+    }
+
+    output.push('\n');
+}
+
+fn write_alpha_luminance(output: &mut String, alpha: Option<u8>, luminance: Option<u8>) {
+    if let Some(alpha) = alpha {
+        write!(output, " ALPHA {alpha}").unwrap();
+    }
+    if let Some(luminance) = luminance {
+        write!(output, " LUMINANCE {luminance}").unwrap();
+    }
+}
+
+fn write_grain_size(output: &mut String, size: &GrainSize) {
+    match *size {
+        GrainSize::Size(size) => write!(output, " SIZE {}", number(size)).unwrap(),
+        GrainSize::MinMaxSize((min, max)) => {
+            write!(output, " MINSIZE {} MAXSIZE {}", number(min), number(max)).unwrap()
+        }
+    }
+}
+
+fn hex(color: Color) -> String {
+    format!("#{:02X}{:02X}{:02X}", color.red, color.green, color.blue)
+}
+
+/// Direct colors are only recognized in hex.
+fn color_code(code: ColorCode) -> String {
+    if code >= 0x200_0000 {
+        format!("0x{code:07X}")
+    } else {
+        code.to_string()
+    }
+}
+
+/// Formats a number without the noise that composing transforms leaves behind,
+/// like `0.99999994` or `-0`.
+fn number(n: f32) -> String {
+    let s = format!("{n:.6}");
+    let s = s.trim_end_matches('0').trim_end_matches('.');
+    match s {
+        "-0" => "0".into(),
+        s => s.into(),
+    }
+}
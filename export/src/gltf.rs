@@ -0,0 +1,238 @@
+use std::collections::HashMap;
+
+use ldr2pdf_common::ldr::{ColorCode, ColorMap};
+use serde_json::{Value, json};
+use weldr::{Color, Mat4, Vec3};
+
+use crate::Scene;
+
+const FLOAT: u32 = 5126;
+const ARRAY_BUFFER: u32 = 34962;
+
+/// A glTF 2.0 document and the binary buffer it refers to.
+pub struct Gltf {
+    pub json: Value,
+    pub buffer: Vec<u8>,
+}
+
+/// Lays out a scene with one mesh per part and color it appears in, and one node per instance,
+/// all under a root node applying `transform`.
+///
+/// A mesh per part alone isn't enough, since glTF materials are chosen by the mesh,
+/// not the node using it.
+pub fn build_gltf(scene: &Scene, colors: &ColorMap, transform: Mat4) -> Gltf {
+    let mut builder = Builder::default();
+    // instances that are inverted or unclipped need their triangles wound differently
+    let mut meshes: HashMap<(&str, ColorCode, bool, bool), Option<usize>> = HashMap::new();
+    let mut children = vec![];
+
+    for instance in &scene.instances {
+        let mesh = *meshes
+            .entry((
+                instance.file.as_str(),
+                instance.color,
+                instance.inverted,
+                instance.clip,
+            ))
+            .or_insert_with(|| {
+                let geometry = &scene.parts[&instance.file];
+                builder.mesh(
+                    &instance.file,
+                    geometry.triangles_by_color(instance),
+                    colors,
+                )
+            });
+        // parts with nothing but edges don't need a node
+        let Some(mesh) = mesh else {
+            continue;
+        };
+        children.push(builder.nodes.len());
+        builder.nodes.push(json!({
+            "name": instance.file,
+            "mesh": mesh,
+            "matrix": instance.transform.to_cols_array(),
+        }));
+    }
+
+    let root = builder.nodes.len();
+    builder.nodes.push(json!({
+        "name": scene.name,
+        "matrix": transform.to_cols_array(),
+        "children": children,
+    }));
+
+    let json = json!({
+        "asset": { "version": "2.0", "generator": "ldr2pdf" },
+        "scene": 0,
+        "scenes": [{ "nodes": [root] }],
+        "nodes": builder.nodes,
+        "meshes": builder.meshes,
+        "materials": builder.materials,
+        "accessors": builder.accessors,
+        "bufferViews": builder.buffer_views,
+        "buffers": [{ "byteLength": builder.buffer.len() }],
+    });
+
+    Gltf {
+        json,
+        buffer: builder.buffer,
+    }
+}
+
+impl Gltf {
+    /// The JSON part of a `.gltf`, with the buffer stored separately at `buffer_uri`.
+    pub fn to_json(&self, buffer_uri: &str) -> String {
+        let mut json = self.json.clone();
+        json["buffers"][0]["uri"] = buffer_uri.into();
+        serde_json::to_string(&json).unwrap()
+    }
+
+    /// A self-contained `.glb`.
+    pub fn to_glb(&self) -> Vec<u8> {
+        let mut json = serde_json::to_vec(&self.json).unwrap();
+        // chunks are padded to 4 bytes, with spaces for JSON and zeros for binary
+        json.resize(json.len().next_multiple_of(4), b' ');
+        let mut buffer = self.buffer.clone();
+        buffer.resize(buffer.len().next_multiple_of(4), 0);
+
+        let length = 12 + 8 + json.len() + 8 + buffer.len();
+        let mut output = Vec::with_capacity(length);
+        output.extend(b"glTF");
+        output.extend(2u32.to_le_bytes());
+        output.extend((length as u32).to_le_bytes());
+        output.extend((json.len() as u32).to_le_bytes());
+        output.extend(b"JSON");
+        output.extend(json);
+        output.extend((buffer.len() as u32).to_le_bytes());
+        output.extend(b"BIN\0");
+        output.extend(buffer);
+        output
+    }
+}
+
+#[derive(Default)]
+struct Builder {
+    nodes: Vec<Value>,
+    meshes: Vec<Value>,
+    materials: Vec<Value>,
+    /// Material indices by color.
+    material_indices: HashMap<ColorCode, usize>,
+    accessors: Vec<Value>,
+    buffer_views: Vec<Value>,
+    buffer: Vec<u8>,
+}
+
+impl Builder {
+    /// Adds a mesh with a primitive for each color, returning its index if it has any triangles.
+    fn mesh(
+        &mut self,
+        name: &str,
+        triangles_by_color: Vec<(ColorCode, Vec<[Vec3; 3]>)>,
+        colors: &ColorMap,
+    ) -> Option<usize> {
+        if triangles_by_color.is_empty() {
+            return None;
+        }
+
+        let primitives = triangles_by_color
+            .into_iter()
+            .map(|(color, triangles)| {
+                let positions = self.positions(triangles.as_flattened());
+                json!({
+                    "attributes": { "POSITION": positions },
+                    "material": self.material(color, colors),
+                })
+            })
+            .collect::<Vec<_>>();
+
+        self.meshes.push(json!({
+            "name": name,
+            "primitives": primitives,
+        }));
+        Some(self.meshes.len() - 1)
+    }
+
+    /// Adds vertex positions to the buffer, returning their accessor.
+    fn positions(&mut self, positions: &[Vec3]) -> usize {
+        let offset = self.buffer.len();
+        for n in positions.iter().flat_map(|v| v.to_array()) {
+            self.buffer.extend(n.to_le_bytes());
+        }
+
+        self.buffer_views.push(json!({
+            "buffer": 0,
+            "byteOffset": offset,
+            "byteLength": self.buffer.len() - offset,
+            "target": ARRAY_BUFFER,
+        }));
+
+        // bounds are required for positions
+        let min = positions
+            .iter()
+            .copied()
+            .reduce(Vec3::min)
+            .unwrap_or_default();
+        let max = positions
+            .iter()
+            .copied()
+            .reduce(Vec3::max)
+            .unwrap_or_default();
+        self.accessors.push(json!({
+            "bufferView": self.buffer_views.len() - 1,
+            "componentType": FLOAT,
+            "count": positions.len(),
+            "type": "VEC3",
+            "min": min.to_array(),
+            "max": max.to_array(),
+        }));
+        self.accessors.len() - 1
+    }
+
+    fn material(&mut self, code: ColorCode, colors: &ColorMap) -> usize {
+        if let Some(&i) = self.material_indices.get(&code) {
+            return i;
+        }
+
+        let material = match colors.material(code) {
+            Ok(m) => {
+                let [r, g, b] = linear(m.average_color());
+                let alpha = m.alpha as f32 / 255.0;
+                let luminance = m.luminance as f32 / 255.0;
+                let name = colors.by_code(code).map(|c| c.name.clone());
+                json!({
+                    "name": name.unwrap_or_else(|_| code.to_string()),
+                    "pbrMetallicRoughness": {
+                        "baseColorFactor": [r, g, b, alpha],
+                        "metallicFactor": m.metallic(),
+                        "roughnessFactor": m.roughness(),
+                    },
+                    "emissiveFactor": [r * luminance, g * luminance, b * luminance],
+                    "alphaMode": if m.is_translucent() { "BLEND" } else { "OPAQUE" },
+                })
+            }
+            Err(e) => {
+                eprintln!("{e}");
+                json!({
+                    "name": code.to_string(),
+                    "pbrMetallicRoughness": { "baseColorFactor": [1.0, 0.0, 1.0, 1.0] },
+                })
+            }
+        };
+
+        self.materials.push(material);
+        self.material_indices.insert(code, self.materials.len() - 1);
+        self.materials.len() - 1
+    }
+}
+
+/// glTF color factors are linear, where LDraw's colors are sRGB.
+fn linear(color: Color) -> [f32; 3] {
+    [color.red, color.green, color.blue].map(|n| {
+        let c = n as f32 / 255.0;
+        if c <= 0.04045 {
+            c / 12.92
+        } else {
+            ((c + 0.055) / 1.055).powf(2.4)
+        }
+    })
+}
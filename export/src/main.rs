@@ -0,0 +1,123 @@
+mod gltf;
+mod obj;
+mod stl;
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use ldr2pdf_common::Result;
+use ldr2pdf_common::cache::PartCache;
+use ldr2pdf_common::geometry::{PartGeometry, part_instances};
+use ldr2pdf_common::ldr::{ColorMap, Palette};
+use ldr2pdf_common::resolver::{Resolver, ResolverConfig};
+use ldr2pdf_common::steps::Placement;
+
+use weldr::{Mat4, SourceMap, Vec3};
+
+/// LDraw units are 0.4mm.
+const MM_PER_LDU: f32 = 0.4;
+
+/// Each part's geometry, once, and where every instance of it goes.
+pub struct Scene {
+    pub name: String,
+    pub parts: BTreeMap<String, PartGeometry>,
+    pub instances: Vec<Placement>,
+}
+
+fn main() -> Result<()> {
+    let mut args = std::env::args().skip(1);
+    let (Some(input), Some(output)) = (args.next(), args.next()) else {
+        eprintln!("usage: ldr2pdf_export <model> <output.gltf|.glb|.obj|.stl> [submodel]");
+        std::process::exit(2);
+    };
+    let output = Path::new(&output);
+
+    let config = ResolverConfig {
+        substitute_missing: true,
+        ..ResolverConfig::from_env()
+    };
+    let resolver = Resolver::new(&input, config)?;
+    let mut source_map = SourceMap::new();
+    let file_name = Path::new(&input).file_name().unwrap_or_default();
+    let main_model_name = weldr::parse(file_name, &PartCache::new(&resolver), &mut source_map)?;
+    let main_model_name = resolver.choose_model(main_model_name, args.next().as_deref())?;
+    for missing in resolver.missing_report(&source_map, &main_model_name) {
+        eprintln!("{missing}");
+    }
+
+    let mut color_map = ColorMap::from_resolver(&resolver, Palette::Rgb)?;
+    color_map.add_model_colors(&source_map, &main_model_name);
+
+    let scene = build_scene(&source_map, &main_model_name)?;
+
+    let extension = output
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or_default();
+    match extension.to_lowercase().as_str() {
+        "gltf" => {
+            let gltf = gltf::build_gltf(&scene, &color_map, y_up(MM_PER_LDU / 1000.0));
+            let buffer_path = output.with_extension("bin");
+            let buffer_uri = buffer_path.file_name().unwrap().to_string_lossy();
+            std::fs::write(output, gltf.to_json(&buffer_uri))?;
+            std::fs::write(&buffer_path, &gltf.buffer)?;
+        }
+        "glb" => {
+            let gltf = gltf::build_gltf(&scene, &color_map, y_up(MM_PER_LDU / 1000.0));
+            std::fs::write(output, gltf.to_glb())?;
+        }
+        "obj" => {
+            let mtl_path = output.with_extension("mtl");
+            let mtl_name = mtl_path.file_name().unwrap().to_string_lossy();
+            let (obj, mtl) = obj::build_obj(&scene, &color_map, &mtl_name, y_up(MM_PER_LDU));
+            std::fs::write(output, obj)?;
+            std::fs::write(&mtl_path, mtl)?;
+        }
+        "stl" => {
+            // slicers expect z to point up
+            #[rustfmt::skip]
+            let z_up = Mat4::from_cols_array(&[
+                1.0, 0.0, 0.0, 0.0,
+                0.0, 0.0, -1.0, 0.0,
+                0.0, 1.0, 0.0, 0.0,
+                0.0, 0.0, 0.0, 1.0,
+            ]);
+            let transform = Mat4::from_scale(Vec3::splat(MM_PER_LDU)) * z_up;
+            std::fs::write(output, stl::build_stl(&scene, transform))?;
+        }
+        _ => return Err(format!("unknown output format: {}", output.display()).into()),
+    }
+
+    Ok(())
+}
+
+/// From LDraw's space, where y points down, to one where it points up, scaled.
+fn y_up(scale: f32) -> Mat4 {
+    // a half turn about x, which keeps the model the right way round
+    Mat4::from_scale(Vec3::new(scale, -scale, -scale))
+}
+
+fn build_scene(source_map: &SourceMap, model_name: &str) -> Result<Scene> {
+    let mut parts = BTreeMap::new();
+    let mut instances = vec![];
+    for instance in part_instances(source_map, model_name)? {
+        if !parts.contains_key(&instance.file) {
+            match PartGeometry::build(source_map, &instance.file) {
+                Ok(geometry) => {
+                    parts.insert(instance.file.clone(), geometry);
+                }
+                Err(e) => {
+                    eprintln!("{e}");
+                    continue;
+                }
+            }
+        }
+        instances.push(instance);
+    }
+
+    Ok(Scene {
+        name: model_name.to_owned(),
+        parts,
+        instances,
+    })
+}
@@ -0,0 +1,90 @@
+use std::collections::BTreeSet;
+use std::fmt::Write;
+
+use ldr2pdf_common::ldr::{ColorCode, ColorMap};
+use weldr::Mat4;
+
+use crate::Scene;
+
+/// Writes every part instance as its own object, with `transform` applied,
+/// along with a material library of the colors they use.
+///
+/// Returns the OBJ and then the MTL, which the OBJ refers to as `mtl_name`.
+pub fn build_obj(
+    scene: &Scene,
+    colors: &ColorMap,
+    mtl_name: &str,
+    transform: Mat4,
+) -> (String, String) {
+    let mut obj = String::new();
+    writeln!(obj, "mtllib {mtl_name}").unwrap();
+
+    let mut used_colors = BTreeSet::new();
+    for (i, instance) in scene.instances.iter().enumerate() {
+        let transform = transform * instance.transform;
+        let mirrored = transform.determinant() < 0.0;
+        writeln!(
+            obj,
+            "o {}_{i}",
+            instance.file.replace(char::is_whitespace, "_")
+        )
+        .unwrap();
+
+        let geometry = &scene.parts[&instance.file];
+        for (color, triangles) in geometry.triangles_by_color(instance) {
+            used_colors.insert(color);
+            writeln!(obj, "usemtl {}", material_name(color)).unwrap();
+            for triangle in triangles {
+                let mut vertices = triangle.map(|v| transform.transform_point3(v));
+                if mirrored {
+                    vertices.reverse();
+                }
+                for v in vertices {
+                    writeln!(obj, "v {} {} {}", v.x, v.y, v.z).unwrap();
+                }
+                // relative to the end of the vertices so far
+                obj.push_str("f -3 -2 -1\n");
+            }
+        }
+    }
+
+    let mut mtl = String::new();
+    for code in used_colors {
+        writeln!(mtl, "newmtl {}", material_name(code)).unwrap();
+        let material = match colors.material(code) {
+            Ok(material) => material,
+            Err(e) => {
+                eprintln!("{e}");
+                mtl.push_str("Kd 1 0 1\n\n");
+                continue;
+            }
+        };
+        let rgb = material.average_color();
+        let [r, g, b] = [rgb.red, rgb.green, rgb.blue].map(|n| n as f32 / 255.0);
+        writeln!(mtl, "Kd {r} {g} {b}").unwrap();
+        if material.is_translucent() {
+            writeln!(mtl, "d {}", material.alpha as f32 / 255.0).unwrap();
+        }
+        if material.luminance > 0 {
+            let luminance = material.luminance as f32 / 255.0;
+            writeln!(
+                mtl,
+                "Ke {} {} {}",
+                r * luminance,
+                g * luminance,
+                b * luminance
+            )
+            .unwrap();
+        }
+        if let Ok(colour) = colors.by_code(code) {
+            writeln!(mtl, "# {}", colour.name).unwrap();
+        }
+        mtl.push('\n');
+    }
+
+    (obj, mtl)
+}
+
+fn material_name(code: ColorCode) -> String {
+    format!("ldraw_{code}")
+}
@@ -0,0 +1,40 @@
+use ldr2pdf_common::geometry::Polygon;
+use weldr::Mat4;
+
+use crate::Scene;
+
+/// Writes every part's front faces as a binary STL, with `transform` applied.
+///
+/// Double-sided polygons are only written once, as a printed solid has no back faces to show.
+pub fn build_stl(scene: &Scene, transform: Mat4) -> Vec<u8> {
+    // an 80-byte header, then the triangle count
+    let mut output = vec![0; 84];
+    let header = b"ldr2pdf export";
+    output[..header.len()].copy_from_slice(header);
+
+    let mut count = 0u32;
+    for instance in &scene.instances {
+        let transform = transform * instance.transform;
+        let mirrored = instance.inverted ^ (transform.determinant() < 0.0);
+        let polygons = &scene.parts[&instance.file].polygons;
+        for triangle in polygons.iter().flat_map(Polygon::triangles) {
+            let mut vertices = triangle.map(|v| transform.transform_point3(v));
+            if mirrored {
+                vertices.reverse();
+            }
+            let [a, b, c] = vertices;
+            let normal = (b - a).cross(c - a).normalize_or_zero();
+            for v in [normal, a, b, c] {
+                for n in v.to_array() {
+                    output.extend(n.to_le_bytes());
+                }
+            }
+            // attribute byte count, which nothing uses
+            output.extend(0u16.to_le_bytes());
+            count += 1;
+        }
+    }
+
+    output[80..84].copy_from_slice(&count.to_le_bytes());
+    output
+}
@@ -17,6 +17,12 @@ pub struct Instruction {
     pub custom_layouts: (),
 }
 
+impl Instruction {
+    pub fn parse(xml: &str) -> Result<Self, quick_xml::DeError> {
+        quick_xml::de::from_str(xml)
+    }
+}
+
 #[derive(Debug, Default, Serialize, Deserialize)]
 #[serde(rename_all = "PascalCase")]
 pub struct GlobalSettings {
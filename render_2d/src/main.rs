@@ -1,31 +1,64 @@
 mod pdf;
 
-use ldr2pdf_common::ldr::{self, ColorMap, GeometryContext};
-use ldr2pdf_common::resolver::Resolver;
-use ldr2pdf_common::{Primitive, Result};
+use ldr2pdf_common::cache::PartCache;
+use ldr2pdf_common::camera::Camera;
+use ldr2pdf_common::geometry::GeometryCache;
+use ldr2pdf_common::ldr::{ColorMap, GeometryContext, Palette};
+use ldr2pdf_common::resolver::{PrimitiveQuality, Resolver, ResolverConfig};
+use ldr2pdf_common::{Primitive, Result, read_model_ins};
+use ldr2pdf_ins_xml::Instruction;
 
 use weldr::SourceMap;
 
 fn main() -> Result<()> {
     // tracing_subscriber::fmt::init();
 
-    let resolver = Resolver::new("/home/the0x539/winhome/Documents/lego/penbu/ket.io")?;
+    let config = ResolverConfig {
+        primitives: PrimitiveQuality::High,
+        substitute_missing: true,
+        ..ResolverConfig::from_env()
+    };
+    let path = "/home/the0x539/winhome/Documents/lego/penbu/ket.io";
+    let resolver = Resolver::new(path, config)?;
     let mut source_map = SourceMap::new();
-    let main_model_name = weldr::parse("ket.io", &resolver, &mut source_map)?;
+    let main_model_name = weldr::parse("ket.io", &PartCache::new(&resolver), &mut source_map)?;
+    let main_model_name =
+        resolver.choose_model(main_model_name, std::env::args().nth(1).as_deref())?;
+    for missing in resolver.missing_report(&source_map, &main_model_name) {
+        eprintln!("{missing}");
+    }
 
-    let color_map = ColorMap::load("/mnt/c/Program Files/Studio 2.0/ldraw/LDConfig.ldr")?;
+    let instruction = read_model_ins(path).ok().map(|xml| Instruction::parse(&xml));
+    // the instructions only pick the palette, so there's no need to give up if they don't parse
+    let palette = match instruction {
+        Some(Ok(ins)) if ins.global_setting.page_setup.use_cmyk_color_table => Palette::Cmyk,
+        Some(Err(e)) => {
+            eprintln!("couldn't read model.ins, using RGB colors: {e}");
+            Palette::Rgb
+        }
+        _ => Palette::Rgb,
+    };
+    let mut color_map = ColorMap::from_resolver(&resolver, palette)?;
+    color_map.add_model_colors(&source_map, &main_model_name);
 
     let mut shapes = Vec::new();
 
-    let ctx = GeometryContext::new();
-    ldr::traverse(&source_map, &main_model_name, ctx, &mut shapes);
+    let camera = match std::env::args().nth(2) {
+        Some(view) => Camera::named(&view).ok_or_else(|| format!("unknown view: {view}"))?,
+        None => Camera::default(),
+    };
+    let ctx = GeometryContext {
+        cull: true,
+        ..GeometryContext::from_camera(&camera)
+    };
+    GeometryCache::new().draw(&source_map, &main_model_name, &ctx, &mut shapes)?;
 
     normalize(&mut shapes);
     for v in shapes.iter_mut().flat_map(|p| p.as_mut_slice()) {
         v[1] = 600.0 - v[1];
     }
 
-    pdf::build_pdf(1, 800, 600, &shapes, &color_map).save("out.pdf")?;
+    pdf::build_pdf(1, 800, 600, &shapes, &color_map)?.save("out.pdf")?;
 
     Ok(())
 }
@@ -1,4 +1,9 @@
-use ldr2pdf_common::{ldr::ColorMap, Point, Primitive};
+use std::collections::BTreeSet;
+
+use ldr2pdf_common::{
+    Point, Primitive,
+    ldr::{ColorMap, UnknownColor},
+};
 
 use lopdf::{
     content::{Content, Operation},
@@ -12,7 +17,7 @@ pub fn build_pdf(
     height: u32,
     drawing: &[Primitive],
     colors: &ColorMap,
-) -> Document {
+) -> Result<Document, UnknownColor> {
     let mut doc = Document::new();
 
     let pages_id = doc.new_object_id();
@@ -28,15 +33,6 @@ pub fn build_pdf(
         page_ids.push(page_id);
     }
 
-    let kids: Vec<Object> = page_ids.iter().copied().map(From::from).collect();
-    let pages = dictionary! {
-        "Type" => "Pages",
-        "Count" => kids.len() as u32,
-        "Kids" => kids,
-        "MediaBox" => [0, 0, width, height].map(From::from).to_vec(),
-    };
-    doc.objects.insert(pages_id, Object::Dictionary(pages));
-
     let mut content = doc.get_and_decode_page_content(page_ids[0]).unwrap();
 
     content.push_op("w", [0.1]);
@@ -45,15 +41,28 @@ pub fn build_pdf(
     content.push_op("J", [1u8]);
 
     let mut current_color = Color::new(255, 255, 255);
+    let mut current_stroke = Color::new(0, 0, 0);
+    let mut current_alpha = 0xFF;
+    let mut alphas = BTreeSet::new();
 
     let mut drawing = drawing.to_vec();
     drawing.sort_by(|a, b| b.center().z.total_cmp(&a.center().z));
 
     for shape in drawing {
         match shape {
-            Primitive::Line(l) => content.push_line(l),
+            Primitive::Line(l, color) => {
+                let rgb = colors.line_color(color)?;
+                content.push_line(l, (rgb != current_stroke).then_some(rgb));
+                current_stroke = rgb;
+            }
             Primitive::Polygon(polygon, color_code) => {
-                let rgb = colors.by_code(color_code).value;
+                let material = colors.material(color_code)?;
+                if material.alpha != current_alpha {
+                    current_alpha = material.alpha;
+                    alphas.insert(current_alpha);
+                    content.push_op("gs", [Object::Name(alpha_state(current_alpha))]);
+                }
+                let rgb = material.average_color();
                 content.push_polygon(polygon.as_slice(), (rgb != current_color).then_some(rgb));
                 current_color = rgb;
             }
@@ -63,13 +72,37 @@ pub fn build_pdf(
     doc.change_page_content(page_ids[0], content.encode().unwrap())
         .unwrap();
 
+    // translucent colors are drawn with graphics states that set the fill alpha
+    let mut states = dictionary! {};
+    for alpha in alphas {
+        let a = alpha as f32 / 255.0;
+        states.set(
+            alpha_state(alpha),
+            dictionary! { "Type" => "ExtGState", "ca" => a },
+        );
+    }
+
+    let kids: Vec<Object> = page_ids.iter().copied().map(From::from).collect();
+    let pages = dictionary! {
+        "Type" => "Pages",
+        "Count" => kids.len() as u32,
+        "Kids" => kids,
+        "MediaBox" => [0, 0, width, height].map(From::from).to_vec(),
+        "Resources" => dictionary! { "ExtGState" => states },
+    };
+    doc.objects.insert(pages_id, Object::Dictionary(pages));
+
     let catalog_id = doc.add_object(dictionary! {
         "Type" => "Catalog",
         "Pages" => pages_id,
     });
     doc.trailer.set("Root", catalog_id);
 
-    doc
+    Ok(doc)
+}
+
+fn alpha_state(alpha: u8) -> Vec<u8> {
+    format!("Alpha{alpha}").into_bytes()
 }
 
 trait ContentExt {
@@ -97,9 +130,17 @@ trait ContentExt {
         self.push_void_op("f");
     }
 
-    fn push_line(&mut self, line: [Point; 2]) {
+    fn push_line(&mut self, line: [Point; 2], color: Option<weldr::Color>) {
         self.push_op("m", [line[0].x, line[0].y]);
         self.push_op("l", [line[1].x, line[1].y]);
+
+        if let Some(rgb) = color {
+            self.push_op(
+                "RG",
+                [rgb.red, rgb.green, rgb.blue].map(|n| n as f32 / 255.0),
+            );
+        }
+
         self.push_void_op("S");
     }
 }
@@ -2,10 +2,13 @@ use bevy_flycam::NoCameraPlayerPlugin;
 use bevy_lines::prelude::*;
 use iyes_perf_ui::prelude::*;
 use ldr2pdf_common::{
-    ldr::{ColorCode, ColorMap, GeometryContext, Winding, new_color},
-    resolver::Resolver,
+    cache::PartCache,
+    geometry::{GeometryCache, part_instances},
+    ldr::{ColorCode, ColorMap, Palette, TraverseError},
+    resolver::{PrimitiveQuality, Resolver, ResolverConfig},
+    steps::Placement,
 };
-use weldr::{Command, SourceMap};
+use weldr::SourceMap;
 
 use bevy::{
     asset::RenderAssetUsages,
@@ -15,7 +18,7 @@ use bevy::{
         RenderPlugin,
         camera::Exposure,
         diagnostic::RenderDiagnosticsPlugin,
-        mesh::PrimitiveTopology,
+        mesh::{Indices, PrimitiveTopology},
         settings::{Backends, RenderCreation, WgpuSettings},
     },
     utils::HashMap,
@@ -56,18 +59,36 @@ fn setup(
     mut lines: ResMut<Assets<Polyline>>,
     mut line_materials: ResMut<Assets<PolylineMaterial>>,
 ) {
-    let resolver = Resolver::new(dirs::document_dir().unwrap().join("lego/aria/HQ.io")).unwrap();
+    let config = ResolverConfig {
+        primitives: PrimitiveQuality::Low,
+        substitute_missing: true,
+        ..ResolverConfig::from_env()
+    };
+    let resolver = Resolver::new(
+        dirs::document_dir().unwrap().join("lego/aria/HQ.io"),
+        config,
+    )
+    .unwrap();
     let mut source_map = SourceMap::new();
-    let main_model_name = weldr::parse("HQ.io", &resolver, &mut source_map).unwrap();
+    let main_model_name =
+        weldr::parse("HQ.io", &PartCache::new(&resolver), &mut source_map).unwrap();
+    let main_model_name = resolver
+        .choose_model(main_model_name, std::env::args().nth(1).as_deref())
+        .unwrap();
+    for missing in resolver.missing_report(&source_map, &main_model_name) {
+        warn!("{missing}");
+    }
 
-    let color_map = ColorMap::load("C:/Program Files/Studio 2.0/ldraw/LDConfig.ldr").unwrap();
+    let mut color_map = ColorMap::from_resolver(&resolver, Palette::Rgb).unwrap();
+    color_map.add_model_colors(&source_map, &main_model_name);
 
-    let mut ctx = GeometryContext::new();
-    ctx.transform = weldr::Mat4::from_rotation_z(std::f32::consts::PI)
+    let transform = weldr::Mat4::from_rotation_z(std::f32::consts::PI)
         * weldr::Mat4::from_scale(weldr::Vec3::splat(0.05));
 
-    let mut parts = Vec::new();
-    traverse_design(&source_map, &main_model_name, ctx.clone(), &mut parts);
+    let parts = part_instances(&source_map, &main_model_name).unwrap_or_else(|e| {
+        error!("{e}");
+        vec![]
+    });
 
     let mut handles = Handles::default();
     let line_material = line_materials.add(PolylineMaterial {
@@ -82,11 +103,15 @@ fn setup(
     });
 
     for part in &parts {
-        handles.load_part(&source_map, &part.id, &mut meshes, &mut lines);
+        if let Err(e) = handles.load_part(&source_map, part, &mut meshes, &mut lines) {
+            error!("{e}");
+            continue;
+        }
         handles.load_material(&color_map, part.color, &mut materials);
         handles.spawn_part(
             &mut commands,
             part,
+            transform,
             line_material.clone(),
             opt_line_material.clone(),
         );
@@ -117,7 +142,10 @@ fn setup(
 
 #[derive(Default)]
 struct Handles {
-    part: HashMap<String, PartHandles>,
+    geometry: GeometryCache,
+    /// By file, and whether the instances are inverted and have clipping on,
+    /// which change the mesh's windings.
+    part: HashMap<(String, bool, bool), PartHandles>,
     material: HashMap<ColorCode, Handle<StandardMaterial>>,
 }
 
@@ -132,48 +160,74 @@ impl Handles {
     fn load_part(
         &mut self,
         source_map: &SourceMap,
-        part_id: &str,
+        part: &Placement,
         meshes: &mut Assets<Mesh>,
         lines: &mut Assets<Polyline>,
-    ) {
-        if self.part.contains_key(part_id) {
-            return;
+    ) -> Result<(), TraverseError> {
+        let key = part_key(part);
+        if self.part.contains_key(&key) {
+            return Ok(());
         }
 
-        let primitives = build_part_mesh(&source_map, &part_id);
+        // TODO: color of individual polygons
+        let geometry = self.geometry.get(source_map, &part.file)?;
 
+        let smooth = geometry.smooth_mesh(part);
         let mesh = Mesh::new(
             PrimitiveTopology::TriangleList,
             RenderAssetUsages::default(),
         )
         .with_inserted_attribute(
             Mesh::ATTRIBUTE_POSITION,
-            primitives
-                .triangles
+            smooth
+                .positions
+                .iter()
+                .map(weldr::Vec3::to_array)
+                .collect::<Vec<_>>(),
+        )
+        .with_inserted_attribute(
+            Mesh::ATTRIBUTE_NORMAL,
+            smooth
+                .normals
                 .iter()
-                .flatten()
-                .map(Vec3::to_array)
+                .map(weldr::Vec3::to_array)
                 .collect::<Vec<_>>(),
         )
-        .with_computed_normals();
+        .with_inserted_indices(Indices::U32(smooth.indices));
 
         let line = Polyline {
-            vertices: primitives.lines.into_iter().flatten().collect(),
+            vertices: geometry
+                .lines
+                .iter()
+                .flat_map(|v| v.0.map(bevy_from_weldr))
+                .collect(),
             control_vertices: None,
         };
         let opt_line = Polyline {
-            vertices: primitives.opt_lines.iter().flat_map(|v| v.0).collect(),
-            control_vertices: Some(primitives.opt_lines.iter().flat_map(|v| v.1).collect()),
+            vertices: geometry
+                .opt_lines
+                .iter()
+                .flat_map(|v| v.0.map(bevy_from_weldr))
+                .collect(),
+            control_vertices: Some(
+                geometry
+                    .opt_lines
+                    .iter()
+                    .flat_map(|v| v.1.map(bevy_from_weldr))
+                    .collect(),
+            ),
         };
 
         self.part.insert(
-            part_id.to_owned(),
+            key,
             PartHandles {
                 mesh: meshes.add(mesh),
                 line: lines.add(line),
                 opt_line: lines.add(opt_line),
             },
         );
+
+        Ok(())
     }
 
     fn load_material(
@@ -186,28 +240,48 @@ impl Handles {
             return;
         }
 
-        let ldraw_color = color_map.by_code(part_color);
-        let rgb = ldraw_color.value;
-        let alpha = ldraw_color.alpha.unwrap_or(0xFF);
-        let [r, g, b, a] = [rgb.red, rgb.green, rgb.blue, alpha].map(|n| n as f32 / 255.0);
-        let color = Color::srgba(r, g, b, a);
-        self.material.insert(part_color, materials.add(color));
+        let material = match color_map.material(part_color) {
+            Ok(m) => {
+                let rgb = m.average_color();
+                let [r, g, b, a] =
+                    [rgb.red, rgb.green, rgb.blue, m.alpha].map(|n| n as f32 / 255.0);
+                let base_color = Color::srgba(r, g, b, a);
+                StandardMaterial {
+                    base_color,
+                    metallic: m.metallic(),
+                    perceptual_roughness: m.roughness(),
+                    emissive: base_color.to_linear() * (m.luminance as f32 / 255.0),
+                    alpha_mode: if m.is_translucent() {
+                        AlphaMode::Blend
+                    } else {
+                        AlphaMode::Opaque
+                    },
+                    ..default()
+                }
+            }
+            Err(e) => {
+                warn!("{e}");
+                Color::srgb(1.0, 0.0, 1.0).into()
+            }
+        };
+        self.material.insert(part_color, materials.add(material));
     }
 
     fn spawn_part(
         &self,
         commands: &mut Commands,
-        part: &Part,
+        part: &Placement,
+        transform: weldr::Mat4,
         line_material: Handle<PolylineMaterial>,
         opt_line_material: Handle<PolylineMaterial>,
     ) {
-        let ph = self.part[&part.id].clone();
+        let ph = self.part[&part_key(part)].clone();
 
         let material = MeshMaterial3d(self.material[&part.color].clone());
         let line_material = PolylineMaterialHandle(line_material.clone());
         let opt_line_material = PolylineMaterialHandle(opt_line_material.clone());
 
-        let transform = Transform::from_matrix(part.transform);
+        let transform = Transform::from_matrix(bevy_from_weldr_mat(transform * part.transform));
 
         commands
             .spawn((Mesh3d(ph.mesh), material.clone(), transform))
@@ -227,44 +301,8 @@ impl Handles {
     }
 }
 
-struct Part {
-    id: String,
-    color: ColorCode,
-    transform: bevy::prelude::Mat4,
-}
-
-fn traverse_design(
-    source_map: &SourceMap,
-    model_name: &str,
-    ctx: GeometryContext,
-    output: &mut Vec<Part>,
-) {
-    let Some(model) = source_map.get(model_name) else {
-        panic!("{model_name}");
-    };
-
-    for cmd in &model.cmds {
-        match cmd {
-            Command::Comment(..) => {}
-            Command::SubFileRef(sfrc) => {
-                let child_ctx = ctx.child(sfrc, false);
-                if sfrc.file.ends_with(".dat") {
-                    let part = Part {
-                        id: sfrc.file.clone(),
-                        // TODO: respect currentcolor
-                        color: new_color(child_ctx.color, sfrc.color),
-                        transform: bevy_from_weldr_mat(child_ctx.transform),
-                    };
-                    output.push(part);
-                } else {
-                    traverse_design(source_map, &sfrc.file, child_ctx, output);
-                }
-            }
-            Command::Line(_) | Command::OptLine(_) => panic!("line in {model_name}"),
-            Command::Triangle(_) | Command::Quad(_) => panic!("polygon in {model_name}"),
-            _ => {}
-        }
-    }
+fn part_key(part: &Placement) -> (String, bool, bool) {
+    (part.file.clone(), part.inverted, part.clip)
 }
 
 fn bevy_from_weldr(a: weldr::Vec3) -> bevy::prelude::Vec3 {
@@ -274,98 +312,3 @@ fn bevy_from_weldr(a: weldr::Vec3) -> bevy::prelude::Vec3 {
 fn bevy_from_weldr_mat(a: weldr::Mat4) -> bevy::prelude::Mat4 {
     bevy::prelude::Mat4::from_cols_array(&a.to_cols_array())
 }
-
-#[derive(Default)]
-struct Primitives {
-    triangles: Vec<[Vec3; 3]>,
-    lines: Vec<[Vec3; 2]>,
-    opt_lines: Vec<([Vec3; 2], [Vec3; 2])>,
-}
-
-fn build_part_mesh(source_map: &SourceMap, model_name: &str) -> Primitives {
-    let mut primitives = Primitives::default();
-    let mut ctx = GeometryContext::new();
-    ctx.transform = weldr::Mat4::IDENTITY;
-    traverse_part(source_map, model_name, ctx, &mut primitives);
-    primitives
-}
-
-fn traverse_part(
-    source_map: &SourceMap,
-    model_name: &str,
-    ctx: GeometryContext,
-    output: &mut Primitives,
-) {
-    let Some(model) = source_map.get(model_name) else {
-        panic!("{model_name}");
-    };
-
-    let mut current_winding = Winding::Ccw;
-    let mut current_inverted = ctx.inverted;
-
-    if ctx.transform.determinant() < 0.0 {
-        current_inverted = !current_inverted;
-    }
-
-    let mut invert_next = false;
-
-    for cmd in &model.cmds {
-        let effective_winding = if current_inverted {
-            !current_winding
-        } else {
-            current_winding
-        };
-
-        match cmd {
-            Command::Comment(c) => {
-                if c.text.starts_with("BFC CERTIFY") {
-                    current_winding = match &*c.text {
-                        "BFC CERTIFY CCW" => Winding::Ccw,
-                        "BFC CERTIFY CW" => Winding::Cw,
-                        _ => panic!("{}", c.text),
-                    };
-                } else if c.text.contains("BFC INVERTNEXT") {
-                    invert_next = true;
-                }
-            }
-            Command::SubFileRef(sfrc) => {
-                let child = ctx.child(sfrc, invert_next);
-                traverse_part(source_map, &sfrc.file, child, output);
-                invert_next = false;
-            }
-            Command::Line(l) => output
-                .lines
-                .push(ctx.project(l.vertices).map(bevy_from_weldr)),
-
-            Command::OptLine(l) => {
-                let [vertices, control_points] =
-                    [l.vertices, l.control_points].map(|x| ctx.project(x).map(bevy_from_weldr));
-                output.opt_lines.push((vertices, control_points));
-            }
-            Command::Triangle(t) => {
-                assert!(!invert_next);
-
-                // TODO: color of individual polygons
-                let [a, b, c] = ctx.project(t.vertices).map(bevy_from_weldr);
-                let to_push = if effective_winding == Winding::Ccw {
-                    [a, b, c]
-                } else {
-                    [c, b, a]
-                };
-                output.triangles.push(to_push);
-            }
-            Command::Quad(q) => {
-                assert!(!invert_next);
-
-                let [a, b, c, d] = ctx.project(q.vertices).map(bevy_from_weldr);
-                let to_push = if effective_winding == Winding::Ccw {
-                    [[a, b, c], [c, d, a]]
-                } else {
-                    [[c, b, a], [a, d, c]]
-                };
-                output.triangles.extend(to_push);
-            }
-            _ => {}
-        }
-    }
-}